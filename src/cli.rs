@@ -15,6 +15,15 @@ pub struct Opts {
     /// Git dir to operate on
     #[structopt(long, env = "GIT_DIR")]
     pub git_dir: String,
+    /// Point the remote HEAD at this branch and exit, instead of talking to git
+    #[structopt(long)]
+    pub set_head: Option<String>,
+    /// Move every remote ref into a single manifest object and exit, allowing atomic pushes
+    #[structopt(long)]
+    pub init_manifest: bool,
+    /// Move loose objects written by older versions into a pack and exit
+    #[structopt(long)]
+    pub migrate_objects: bool,
     /// Enable verbose logging (-v, -vv, -vvv, etc)
     #[structopt(short, long, parse(from_occurrences))]
     pub verbose: usize,
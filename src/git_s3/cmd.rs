@@ -1,4 +1,4 @@
-/// Mod to run git commands live in repository
+//! Mod to run git commands live in repository
 
 use anyhow::{Context, Result};
 use std::path::Path;
@@ -0,0 +1,162 @@
+use anyhow::{Context, Error, Result};
+use std::fs;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::Path;
+use std::str::FromStr;
+
+/// How packs are compressed in the bucket. Each codec's stream starts with its own magic bytes,
+/// which is how a pack records the codec it was stored with
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Codec {
+    /// Stored as is
+    None,
+    /// zlib, as git itself uses
+    Zlib,
+    /// Zstandard
+    Zstd,
+    /// Snappy, in its framing format
+    Snappy,
+}
+
+/// Level used by zlib and zstd. Packs are written once and read many times, so it's worth
+/// spending the time on smaller uploads
+const LEVEL: u32 = 9;
+
+/// Magic bytes starting a zstd frame
+const ZSTD_MAGIC: &[u8] = &[0x28, 0xb5, 0x2f, 0xfd];
+
+/// Stream identifier chunk starting a snappy framed stream
+const SNAPPY_MAGIC: &[u8] = b"\xff\x06\x00\x00sNaPpY";
+
+impl FromStr for Codec {
+    type Err = Error;
+
+    fn from_str(name: &str) -> Result<Self> {
+        match name {
+            "none" => Ok(Codec::None),
+            "zlib" => Ok(Codec::Zlib),
+            "zstd" => Ok(Codec::Zstd),
+            "snappy" => Ok(Codec::Snappy),
+            _ => Err(Error::msg(format!("Unknown codec \'{}\', expected none, zlib, zstd or snappy", name))),
+        }
+    }
+}
+
+impl Codec {
+    /// Find the codec data starting with `magic` was compressed with. Anything not starting with
+    /// a codec's magic bytes, such as a raw pack starting with `PACK`, is taken as uncompressed
+    pub fn detect(magic: &[u8]) -> Self {
+        if magic.starts_with(ZSTD_MAGIC) {
+            Codec::Zstd
+        } else if magic.starts_with(SNAPPY_MAGIC) {
+            Codec::Snappy
+        // A zlib header is a deflate method byte, then a check making it a multiple of 31
+        } else if magic.len() >= 2 && magic[0] & 0x0f == 8 && magic[0] >> 4 <= 7
+            && (u16::from(magic[0]) << 8 | u16::from(magic[1])) % 31 == 0 {
+            Codec::Zlib
+        } else {
+            Codec::None
+        }
+    }
+
+    /// Compress everything read from `input` into `output`
+    pub fn encode<R: Read, W: Write>(self, mut input: R, output: W) -> io::Result<W> {
+        match self {
+            Codec::None => {
+                let mut output = output;
+                io::copy(&mut input, &mut output)?;
+                Ok(output)
+            },
+            Codec::Zlib => {
+                let mut encoder = flate2::write::ZlibEncoder::new(output, flate2::Compression::new(LEVEL));
+                io::copy(&mut input, &mut encoder)?;
+                encoder.finish()
+            },
+            Codec::Zstd => {
+                let mut encoder = zstd::Encoder::new(output, LEVEL as i32)?;
+                io::copy(&mut input, &mut encoder)?;
+                encoder.finish()
+            },
+            Codec::Snappy => {
+                let mut encoder = snap::write::FrameEncoder::new(output);
+                io::copy(&mut input, &mut encoder)?;
+                encoder.into_inner().map_err(|e| e.into_error())
+            },
+        }
+    }
+
+    /// Decompress everything read from `input`, compressed with this codec, into `output`
+    pub fn decode<R: Read, W: Write>(self, input: R, mut output: W) -> io::Result<W> {
+        match self {
+            Codec::None => io::copy(&mut { input }, &mut output),
+            Codec::Zlib => io::copy(&mut flate2::read::ZlibDecoder::new(input), &mut output),
+            Codec::Zstd => io::copy(&mut zstd::Decoder::new(input)?, &mut output),
+            Codec::Snappy => io::copy(&mut snap::read::FrameDecoder::new(input), &mut output),
+        }?;
+        Ok(output)
+    }
+}
+
+/// Compress the file at `src` with `codec` into a new file at `dst`
+pub fn encode_file(codec: Codec, src: &Path, dst: &Path) -> Result<()> {
+    let input = fs::File::open(src)
+        .with_context(|| format!("Unable to open {:?}", src))?;
+    let output = fs::File::create(dst)
+        .with_context(|| format!("Unable to create {:?}", dst))?;
+    codec.encode(BufReader::new(input), BufWriter::new(output))?
+        .flush()
+        .with_context(|| format!("Unable to compress {:?} into {:?}", src, dst))
+}
+
+/// Decompress the file at `src`, with whichever codec its content starts with, into `dst`. An
+/// uncompressed file is moved instead. `src` is gone afterwards either way
+pub fn decode_file(src: &Path, dst: &Path) -> Result<Codec> {
+    let mut input = fs::File::open(src)
+        .with_context(|| format!("Unable to open {:?}", src))?;
+    let mut magic = Vec::new();
+    (&mut input).take(SNAPPY_MAGIC.len() as u64).read_to_end(&mut magic)
+        .with_context(|| format!("Unable to read {:?}", src))?;
+    let codec = Codec::detect(&magic);
+    let result = match codec {
+        Codec::None => fs::rename(src, dst)
+            .with_context(|| format!("Unable to move {:?} to {:?}", src, dst)),
+        _ => fs::File::create(dst)
+            .with_context(|| format!("Unable to create {:?}", dst))
+            .and_then(|output| {
+                // The magic bytes were already read off the file
+                let input = io::Cursor::new(magic).chain(BufReader::new(input));
+                codec.decode(input, BufWriter::new(output))?.flush()?;
+                Ok(())
+            })
+            .with_context(|| format!("Unable to decompress {:?} as {:?}", src, codec)),
+    };
+    fs::remove_file(src).ok();
+    result.map(|_| codec)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_trip() {
+        let data = b"PACK\x00\x00\x00\x02".repeat(1000);
+        for codec in &[Codec::None, Codec::Zlib, Codec::Zstd, Codec::Snappy] {
+            let encoded = codec.encode(&data[..], Vec::new()).unwrap();
+            assert_eq!(Codec::detect(&encoded), *codec);
+            assert_eq!(codec.decode(&encoded[..], Vec::new()).unwrap(), data);
+        }
+    }
+    #[test]
+    fn test_detect_uncompressed() {
+        assert_eq!(Codec::detect(b"PACK\x00\x00\x00\x02"), Codec::None);
+        // Pack index v2 header
+        assert_eq!(Codec::detect(b"\xfftOc\x00\x00\x00\x02"), Codec::None);
+        assert_eq!(Codec::detect(b""), Codec::None);
+    }
+    #[test]
+    fn test_parse_codec() {
+        assert_eq!("zstd".parse::<Codec>().unwrap(), Codec::Zstd);
+        assert!("lz4".parse::<Codec>().is_err());
+    }
+}
@@ -0,0 +1,359 @@
+//! Settings from the config file, git config and the environment, merged into what a remote
+//! runs with
+
+use super::util::BucketStyle;
+
+use log::debug;
+use anyhow::{Context, Error, Result};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::env;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// Settings of a remote. Anything left unset falls back to the next place it can be set, see
+/// `Settings::or`
+#[derive(Debug, Default, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Settings {
+    /// Host, `host:port` or URL of the S3 service, or an AWS region name
+    pub endpoint: Option<String>,
+    /// Region requests are signed for
+    pub region: Option<String>,
+    /// Profile in the AWS credentials file to use
+    pub profile: Option<String>,
+    /// Address the bucket in the path, or as a subdomain of the endpoint
+    pub style: Option<BucketStyle>,
+    /// Storage class packs and their indexes are written with
+    pub storage_class: Option<String>,
+    /// Most requests to the bucket in flight at once
+    pub parallelism: Option<usize>,
+    /// Codec packs are compressed with when uploaded
+    pub compression: Option<String>,
+    /// Key encrypting everything in the bucket, as hex
+    pub encryption_key: Option<String>,
+    /// File holding the encryption key
+    pub encryption_key_file: Option<String>,
+    /// Refuse to update refs in buckets that ignore conditional writes
+    pub require_conditional_writes: Option<bool>,
+}
+
+impl Settings {
+    /// Fill in everything unset from `fallback`. The encryption key is a single setting, however
+    /// it is given
+    pub fn or(self, fallback: Settings) -> Settings {
+        let (encryption_key, encryption_key_file) =
+            if self.encryption_key.is_some() || self.encryption_key_file.is_some() {
+                (self.encryption_key, self.encryption_key_file)
+            } else {
+                (fallback.encryption_key, fallback.encryption_key_file)
+            };
+        Settings {
+            endpoint: self.endpoint.or(fallback.endpoint),
+            region: self.region.or(fallback.region),
+            profile: self.profile.or(fallback.profile),
+            style: self.style.or(fallback.style),
+            storage_class: self.storage_class.or(fallback.storage_class),
+            parallelism: self.parallelism.or(fallback.parallelism),
+            compression: self.compression.or(fallback.compression),
+            encryption_key,
+            encryption_key_file,
+            require_conditional_writes: self.require_conditional_writes.or(fallback.require_conditional_writes),
+        }
+    }
+
+    /// Settings of a remote from every place they can be given, `git` config and the config
+    /// `file` before the environment. The first two can name the remote or its bucket, so an
+    /// exported `AWS_REGION` only fills in what neither sets
+    pub fn layered(git: Settings, file: Settings, env: Settings) -> Settings {
+        git.or(file).or(env)
+    }
+
+    /// Settings from the environment. `AWS_PROFILE` isn't one, as credentials in the environment
+    /// win over it, see `creds::load`
+    pub fn from_env() -> Settings {
+        Settings {
+            region: env::var("AWS_REGION").ok(),
+            ..Settings::default()
+        }
+    }
+
+    /// Settings from the git config of the repository at `git_dir`, as git would read it:
+    /// global and system files, includes, `GIT_CONFIG_*` overrides and `git -c` options.
+    /// `remote.<remote>.s3<name>` settings come first, then `s3.<name>` ones
+    pub fn from_git_config(git_dir: &Path, remote: &str) -> Result<Settings> {
+        let mut config = gix_config::File::from_git_dir(git_dir.to_path_buf())
+            .map_err(|e| Error::msg(e.to_string()))
+            .context("Unable to read git config")?;
+        // Git hands `-c` options down to the helper in the environment
+        if let Ok(params) = env::var("GIT_CONFIG_PARAMETERS") {
+            for (name, value) in config_parameters(&params) {
+                let key = match gix_config::KeyRef::parse_unvalidated(name.as_str().into()) {
+                    Some(key) => key,
+                    None => continue,
+                };
+                // A key without a value is a true boolean
+                config.set_raw_value_by(
+                    key.section_name, key.subsection_name, key.value_name,
+                    value.as_deref().unwrap_or("true"),
+                ).map_err(|e| Error::msg(format!("Invalid git -c option {}: {}", name, e)))?;
+            }
+        }
+        let remote_settings = git_settings(&config, &format!("remote.{}.s3", remote))?;
+        Ok(remote_settings.or(git_settings(&config, "s3.")?))
+    }
+}
+
+/// Settings from git config keys starting with `prefix`, either `s3.` or `remote.<name>.s3`
+fn git_settings(config: &gix_config::File, prefix: &str) -> Result<Settings> {
+    let string = |name: &str| config.string(format!("{}{}", prefix, name).as_str())
+        .map(|value| value.to_string());
+    let parallelism = match config.integer(format!("{}parallelism", prefix).as_str()) {
+        Ok(Some(n)) => Some(usize::try_from(n)
+            .map_err(|_| Error::msg(format!("Invalid {}parallelism {}", prefix, n)))?),
+        Ok(None) => None,
+        Err(e) => return Err(Error::msg(format!("Invalid {}parallelism: {}", prefix, e))),
+    };
+    let style = match config.boolean(format!("{}pathStyle", prefix).as_str()) {
+        Ok(Some(true)) => Some(BucketStyle::Path),
+        Ok(Some(false)) => Some(BucketStyle::Subdomain),
+        Ok(None) => None,
+        Err(e) => return Err(Error::msg(format!("Invalid {}pathStyle: {}", prefix, e))),
+    };
+    let require_conditional_writes = config.boolean(format!("{}requireConditionalWrites", prefix).as_str())
+        .map_err(|e| Error::msg(format!("Invalid {}requireConditionalWrites: {}", prefix, e)))?;
+    let encryption_key_file = match config.path(format!("{}encryptionKeyFile", prefix).as_str()) {
+        Some(path) => {
+            let home = env::var_os("HOME").map(PathBuf::from);
+            let context = gix_config::path::interpolate::Context {
+                home_dir: home.as_deref(),
+                ..Default::default()
+            };
+            let path = path.interpolate(context)
+                .map_err(|e| Error::msg(format!("Invalid {}encryptionKeyFile: {}", prefix, e)))?;
+            Some(path.to_string_lossy().into_owned())
+        },
+        None => None,
+    };
+    Ok(Settings {
+        endpoint: string("endpoint"),
+        region: string("region"),
+        profile: string("profile"),
+        style,
+        storage_class: string("storageClass"),
+        parallelism,
+        compression: string("compression"),
+        encryption_key: string("encryptionKey"),
+        encryption_key_file,
+        require_conditional_writes,
+    })
+}
+
+/// Split `GIT_CONFIG_PARAMETERS` into keys and values. Git writes each option shell quoted,
+/// either as `'key'='value'`, `'key=value'`, or `'key'` for a key without a value
+fn config_parameters(params: &str) -> Vec<(String, Option<String>)> {
+    let mut options = Vec::new();
+    // Key, then value once an unquoted `=` is seen
+    let mut parts: Vec<String> = Vec::new();
+    let mut chars = params.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            c if c.is_whitespace() => {
+                if !parts.is_empty() {
+                    options.push(std::mem::take(&mut parts));
+                }
+                continue
+            },
+            '=' if parts.len() == 1 => {
+                parts.push(String::new());
+                continue
+            },
+            _ => (),
+        }
+        if parts.is_empty() {
+            parts.push(String::new());
+        }
+        let current = parts.last_mut().expect("parts were just filled");
+        match c {
+            '\'' => current.extend((&mut chars).take_while(|&c| c != '\'')),
+            '\\' => current.extend(chars.next()),
+            c => current.push(c),
+        }
+    }
+    if !parts.is_empty() {
+        options.push(parts);
+    }
+    options.into_iter()
+        .map(|mut parts| {
+            let value = if parts.len() > 1 { parts.pop() } else { None };
+            let key = parts.pop().unwrap_or_default();
+            match (value, key.find('=')) {
+                // Older git quotes the whole option
+                (None, Some(i)) => (key[..i].to_string(), Some(key[i + 1..].to_string())),
+                (value, _) => (key, value),
+            }
+        })
+        .collect()
+}
+
+/// The config file: settings for every remote at the top level, followed by `[buckets.<name>]`
+/// and `[remotes.<name>]` tables of settings for one bucket or git remote
+#[derive(Debug, Default, PartialEq)]
+pub struct ConfigFile {
+    /// Settings for every remote
+    pub defaults: Settings,
+    /// Settings for remotes using a bucket, by bucket name
+    pub buckets: HashMap<String, Settings>,
+    /// Settings for git remotes, by remote name
+    pub remotes: HashMap<String, Settings>,
+}
+
+impl ConfigFile {
+    /// Load the config file at `path`, where a leading `~` is the home directory. A file that
+    /// doesn't exist has no settings
+    pub fn load(path: &str) -> Result<Self> {
+        let path = expand_home(path);
+        match fs::read_to_string(&path) {
+            Ok(data) => ConfigFile::parse(&data)
+                .with_context(|| format!("Invalid config file {:?}", path)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                debug!("No config file at {:?}", path);
+                Ok(ConfigFile::default())
+            },
+            Err(e) => Err(e).with_context(|| format!("Unable to read config file {:?}", path)),
+        }
+    }
+
+    /// Parse a config file
+    pub fn parse(data: &str) -> Result<Self> {
+        let mut table = match data.parse::<toml::Value>()? {
+            toml::Value::Table(table) => table,
+            _ => return Err(Error::msg("Config file is not a table")),
+        };
+        let buckets = section(table.remove("buckets"), "buckets")?;
+        let remotes = section(table.remove("remotes"), "remotes")?;
+        let defaults = toml::Value::Table(table).try_into()?;
+        Ok(ConfigFile { defaults, buckets, remotes })
+    }
+
+    /// Settings for git remote `remote` using bucket `bucket`, most specific first
+    pub fn settings(&self, remote: &str, bucket: &str) -> Settings {
+        let remote = self.remotes.get(remote).cloned().unwrap_or_default();
+        let bucket = self.buckets.get(bucket).cloned().unwrap_or_default();
+        remote.or(bucket).or(self.defaults.clone())
+    }
+}
+
+/// Parse a table of settings by name
+fn section(value: Option<toml::Value>, name: &str) -> Result<HashMap<String, Settings>> {
+    match value {
+        Some(value) => value.try_into()
+            .with_context(|| format!("Invalid [{}] section", name)),
+        None => Ok(HashMap::new()),
+    }
+}
+
+/// Expand a leading `~` to the home directory
+pub fn expand_home(path: &str) -> PathBuf {
+    match (path.strip_prefix("~/"), env::var_os("HOME")) {
+        (Some(rest), Some(home)) => PathBuf::from(home).join(rest),
+        _ => PathBuf::from(path),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_config_file() {
+        let file = ConfigFile::parse(r#"
+            parallelism = 4
+            compression = "zstd"
+
+            [buckets.shared]
+            endpoint = "https://ceph.example.com"
+            region = "eu-west-2"
+            style = "virtual-host"
+
+            [remotes.origin]
+            profile = "ci"
+            parallelism = 16
+        "#).unwrap();
+        let settings = file.settings("origin", "shared");
+        assert_eq!(settings.parallelism, Some(16));
+        assert_eq!(settings.profile.as_deref(), Some("ci"));
+        assert_eq!(settings.endpoint.as_deref(), Some("https://ceph.example.com"));
+        assert_eq!(settings.style, Some(BucketStyle::Subdomain));
+        assert_eq!(settings.compression.as_deref(), Some("zstd"));
+        assert_eq!(file.settings("other", "other").parallelism, Some(4));
+    }
+    #[test]
+    fn test_invalid_config_file() {
+        assert!(ConfigFile::parse("paralelism = 4").is_err());
+        assert!(ConfigFile::parse("[buckets.b]\nstyle = \"sideways\"").is_err());
+        assert!(ConfigFile::parse("parallelism = \"many\"").is_err());
+    }
+    #[test]
+    fn test_encryption_key_is_one_setting() {
+        let file = Settings { encryption_key_file: Some("key".to_string()), ..Settings::default() };
+        let git = Settings { encryption_key: Some("2a".repeat(32)), ..Settings::default() };
+        let settings = Settings::default().or(file.clone()).or(git);
+        assert_eq!(settings, file);
+    }
+    #[test]
+    fn test_git_settings() {
+        let config: gix_config::File = r#"
+            [s3]
+                parallelism = 4
+                compression = zstd
+                pathStyle = false
+            [remote "origin"]
+                s3pathStyle = true
+                s3storageClass = STANDARD_IA
+                s3requireConditionalWrites
+        "#.parse().unwrap();
+        let settings = git_settings(&config, "remote.origin.s3").unwrap()
+            .or(git_settings(&config, "s3.").unwrap());
+        assert_eq!(settings.parallelism, Some(4));
+        assert_eq!(settings.compression.as_deref(), Some("zstd"));
+        assert_eq!(settings.style, Some(BucketStyle::Path));
+        assert_eq!(settings.storage_class.as_deref(), Some("STANDARD_IA"));
+        assert_eq!(settings.require_conditional_writes, Some(true));
+
+        let config: gix_config::File = "[s3]\nparallelism = -1".parse().unwrap();
+        assert!(git_settings(&config, "s3.").is_err());
+    }
+    #[test]
+    fn test_settings_precedence() {
+        let config: gix_config::File = "[remote \"origin\"]\n\ts3profile = git".parse().unwrap();
+        let file = ConfigFile::parse("[buckets.shared]\nregion = \"eu-west-1\"").unwrap();
+        let env = Settings { region: Some("us-west-2".to_string()), ..Settings::default() };
+        let settings = |remote: &str, bucket| Settings::layered(
+            git_settings(&config, &format!("remote.{}.s3", remote)).unwrap(),
+            file.settings(remote, bucket),
+            env.clone(),
+        );
+        let origin = settings("origin", "shared");
+        assert_eq!(origin.profile.as_deref(), Some("git"));
+        assert_eq!(origin.region.as_deref(), Some("eu-west-1"));
+        let other = settings("other", "other");
+        assert_eq!(other.profile, None);
+        assert_eq!(other.region.as_deref(), Some("us-west-2"));
+    }
+    #[test]
+    fn test_config_parameters() {
+        let options = |params| config_parameters(params);
+        assert_eq!(options(""), vec![]);
+        assert_eq!(options("'s3.parallelism'='4' 'remote.origin.s3pathStyle'"), vec![
+            ("s3.parallelism".to_string(), Some("4".to_string())),
+            ("remote.origin.s3pathStyle".to_string(), None),
+        ]);
+        assert_eq!(options("'s3.profile=it'\\''s' 's3.region'=''"), vec![
+            ("s3.profile".to_string(), Some("it's".to_string())),
+            ("s3.region".to_string(), Some(String::new())),
+        ]);
+    }
+}
@@ -0,0 +1,726 @@
+//! Credentials for the bucket, looked for where the AWS CLI looks for them: the environment, web
+//! identity tokens, profiles in the AWS config files, then container and instance metadata
+
+use super::config::expand_home;
+
+use log::{debug, trace};
+use anyhow::{Context, Error, Result};
+
+use chrono::{DateTime, Duration as Age, Utc};
+use hmac::{Hmac, Mac};
+use ini::{Ini, ParseOption};
+use s3::creds::Credentials;
+use s3::signing::uri_encode;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use std::env;
+use std::fs;
+use std::io;
+use std::process::{Command, Stdio};
+use std::time::Duration;
+use url::Url;
+
+/// Most `source_profile` links followed from one profile, so loops end
+const MAX_PROFILE_DEPTH: usize = 8;
+
+/// How long to wait on the container or instance metadata services. Off AWS, nothing answers at
+/// the instance metadata address
+const METADATA_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Instance metadata service, unless `AWS_EC2_METADATA_SERVICE_ENDPOINT` is set
+const METADATA_ENDPOINT: &str = "http://169.254.169.254";
+
+/// Session name of assumed roles, unless the profile or `AWS_ROLE_SESSION_NAME` sets one
+const SESSION_NAME: &str = "git-remote-s3";
+
+/// How long before they expire temporary credentials are replaced, so requests signed with them
+/// are done before they run out
+const REFRESH_MARGIN_MINUTES: i64 = 5;
+
+/// Credentials along with when they expire, `None` for ones that don't
+#[derive(Debug, Clone, PartialEq)]
+pub struct Expiring {
+    /// Keys requests are signed with
+    pub credentials: Credentials,
+    /// When temporary credentials run out
+    pub expiration: Option<DateTime<Utc>>,
+}
+
+/// Whether credentials expiring at `expiration` are about to, and should be loaded again
+pub fn expires_soon(expiration: Option<DateTime<Utc>>) -> bool {
+    expiration.is_some_and(|expiration| expiration - Utc::now() < Age::minutes(REFRESH_MARGIN_MINUTES))
+}
+
+/// Find credentials for the bucket. With a `profile`, only that profile is used. Otherwise the
+/// first of these that is set up is, and one that is set up but fails is an error:
+/// * `AWS_ACCESS_KEY_ID` and `AWS_SECRET_ACCESS_KEY`, with `AWS_SESSION_TOKEN` if set
+/// * `AWS_WEB_IDENTITY_TOKEN_FILE` and `AWS_ROLE_ARN`, exchanged with STS
+/// * The profile named by `AWS_PROFILE`, or the `default` profile
+/// * The container credentials endpoint, `AWS_CONTAINER_CREDENTIALS_RELATIVE_URI` or
+///   `AWS_CONTAINER_CREDENTIALS_FULL_URI`
+/// * The instance metadata service
+pub fn load(profile: Option<&str>) -> Result<Expiring> {
+    let files = AwsFiles::load()?;
+    if let Some(profile) = profile {
+        debug!("Using credentials of profile \"{}\"", profile);
+        return files.profile_credentials(profile, 0)
+            .with_context(|| format!("Unable to load credentials of profile \"{}\"", profile))
+    }
+    if let Some(credentials) = from_env()? {
+        debug!("Using credentials from the environment");
+        return Ok(credentials)
+    }
+    if let Some(credentials) = from_env_web_identity(&files)? {
+        debug!("Using credentials from AWS_WEB_IDENTITY_TOKEN_FILE");
+        return Ok(credentials)
+    }
+    // Like the AWS CLI, `AWS_PROFILE` doesn't take precedence over keys in the environment
+    if let Some(profile) = env::var("AWS_PROFILE").ok().filter(|profile| !profile.is_empty()) {
+        debug!("Using credentials of profile \"{}\" from AWS_PROFILE", profile);
+        return files.profile_credentials(&profile, 0)
+            .with_context(|| format!("Unable to load credentials of profile \"{}\"", profile))
+    }
+    if files.has_profile("default") {
+        debug!("Using credentials of profile \"default\"");
+        return files.profile_credentials("default", 0)
+            .context("Unable to load credentials of profile \"default\"")
+    }
+    if let Some(credentials) = from_container()? {
+        debug!("Using credentials from the container");
+        return Ok(credentials)
+    }
+    if let Some(credentials) = from_instance_metadata()? {
+        debug!("Using credentials from instance metadata");
+        return Ok(credentials)
+    }
+    Err(Error::msg(
+        "No credentials in the environment, AWS config files, or container or instance metadata"
+    ))
+}
+
+/// Credentials from access keys, and a session token for temporary ones. Expiring ones are
+/// only known to expire if whatever handed them out says when
+fn credentials(access_key: String, secret_key: String, session_token: Option<String>) -> Expiring {
+    Expiring {
+        credentials: Credentials {
+            access_key: Some(access_key),
+            secret_key: Some(secret_key),
+            security_token: None,
+            session_token,
+        },
+        expiration: None,
+    }
+}
+
+/// Environment variable `name`, if set and not empty
+fn var(name: &str) -> Option<String> {
+    env::var(name).ok().filter(|value| !value.is_empty())
+}
+
+/// Credentials from `AWS_ACCESS_KEY_ID`, `AWS_SECRET_ACCESS_KEY` and `AWS_SESSION_TOKEN`
+fn from_env() -> Result<Option<Expiring>> {
+    match (var("AWS_ACCESS_KEY_ID"), var("AWS_SECRET_ACCESS_KEY")) {
+        (Some(access_key), Some(secret_key)) => {
+            let session_token = var("AWS_SESSION_TOKEN").or_else(|| var("AWS_SECURITY_TOKEN"));
+            Ok(Some(credentials(access_key, secret_key, session_token)))
+        },
+        (Some(_), None) => Err(Error::msg("AWS_ACCESS_KEY_ID is set without AWS_SECRET_ACCESS_KEY")),
+        (None, Some(_)) => Err(Error::msg("AWS_SECRET_ACCESS_KEY is set without AWS_ACCESS_KEY_ID")),
+        (None, None) => Ok(None),
+    }
+}
+
+/// Credentials for `AWS_ROLE_ARN` from STS, in exchange for the token in
+/// `AWS_WEB_IDENTITY_TOKEN_FILE`
+fn from_env_web_identity(files: &AwsFiles) -> Result<Option<Expiring>> {
+    let token_file = match var("AWS_WEB_IDENTITY_TOKEN_FILE") {
+        Some(token_file) => token_file,
+        None => return Ok(None),
+    };
+    let role = Role {
+        arn: var("AWS_ROLE_ARN")
+            .context("AWS_WEB_IDENTITY_TOKEN_FILE is set without AWS_ROLE_ARN")?,
+        session_name: var("AWS_ROLE_SESSION_NAME"),
+        external_id: None,
+        duration_seconds: None,
+    };
+    let token = read_token(&token_file)?;
+    Sts::new(files.region("default")).assume_role_with_web_identity(&role, &token).map(Some)
+}
+
+/// Read a token from a file, as written by whatever issues it
+fn read_token(path: &str) -> Result<String> {
+    let path = expand_home(path);
+    let token = fs::read_to_string(&path)
+        .with_context(|| format!("Unable to read token file {:?}", path))?;
+    Ok(token.trim().to_string())
+}
+
+/// The AWS CLI's `credentials` and `config` files. Profile `<name>` is the `[<name>]` section of
+/// the first and `[profile <name>]` of the second
+struct AwsFiles {
+    credentials: Ini,
+    config: Ini,
+}
+
+impl AwsFiles {
+    /// Load the files from where `AWS_SHARED_CREDENTIALS_FILE` and `AWS_CONFIG_FILE` point, or
+    /// `~/.aws`. Files that don't exist have no profiles
+    fn load() -> Result<Self> {
+        Ok(AwsFiles {
+            credentials: load_ini(&var("AWS_SHARED_CREDENTIALS_FILE")
+                .unwrap_or_else(|| "~/.aws/credentials".to_string()))?,
+            config: load_ini(&var("AWS_CONFIG_FILE")
+                .unwrap_or_else(|| "~/.aws/config".to_string()))?,
+        })
+    }
+
+    /// Section of profile `name` in the config file. The default profile's has no prefix
+    fn config_section(&self, name: &str) -> Option<&ini::Properties> {
+        self.config.section(Some(format!("profile {}", name)))
+            .or_else(|| if name == "default" { self.config.section(Some(name)) } else { None })
+    }
+
+    /// Whether profile `name` is in either file
+    fn has_profile(&self, name: &str) -> bool {
+        self.credentials.section(Some(name)).is_some() || self.config_section(name).is_some()
+    }
+
+    /// Setting `key` of profile `name`, from the credentials file before the config file
+    fn get(&self, name: &str, key: &str) -> Option<&str> {
+        self.credentials.get_from(Some(name), key)
+            .or_else(|| self.config_section(name).and_then(|section| section.get(key)))
+    }
+
+    /// Region STS is asked in for profile `name`
+    fn region(&self, name: &str) -> Option<String> {
+        var("AWS_REGION")
+            .or_else(|| var("AWS_DEFAULT_REGION"))
+            .or_else(|| self.get(name, "region").map(String::from))
+    }
+
+    /// Credentials of profile `name`, `depth` links down a chain of `source_profile`s. A role
+    /// is assumed with credentials from its source, a web identity token, or the environment or
+    /// metadata, and a `credential_process` is run
+    fn profile_credentials(&self, name: &str, depth: usize) -> Result<Expiring> {
+        if depth > MAX_PROFILE_DEPTH {
+            return Err(Error::msg("Too many source_profile links, do they loop?"))
+        }
+        if !self.has_profile(name) {
+            return Err(Error::msg(format!("No profile \"{}\" in the AWS config files", name)))
+        }
+        let get = |key| self.get(name, key).map(String::from);
+
+        if let Some(arn) = get("role_arn") {
+            let role = Role {
+                arn,
+                session_name: get("role_session_name"),
+                external_id: get("external_id"),
+                duration_seconds: get("duration_seconds"),
+            };
+            let sts = Sts::new(self.region(name));
+            if let Some(token_file) = get("web_identity_token_file") {
+                return sts.assume_role_with_web_identity(&role, &read_token(&token_file)?)
+            }
+            let source = match (get("source_profile"), get("credential_source")) {
+                // A profile can hold the keys assuming its own role
+                (Some(source), None) if source == name => self.static_credentials(name)?,
+                (Some(source), None) => self.profile_credentials(&source, depth + 1)
+                    .with_context(|| format!("Unable to load credentials of source profile \"{}\"", source))?,
+                (None, Some(source)) => match source.as_str() {
+                    "Environment" => from_env()?,
+                    "EcsContainer" => from_container()?,
+                    "Ec2InstanceMetadata" => from_instance_metadata()?,
+                    _ => return Err(Error::msg(format!("Unknown credential_source \"{}\"", source))),
+                }.with_context(|| format!("No credentials for credential_source \"{}\"", source))?,
+                (Some(_), Some(_)) => return Err(Error::msg(
+                    "Both source_profile and credential_source are set",
+                )),
+                (None, None) => return Err(Error::msg(
+                    "role_arn is set without source_profile, credential_source or web_identity_token_file",
+                )),
+            };
+            return sts.assume_role(&role, &source)
+        }
+        if let Some(command) = get("credential_process") {
+            return credential_process(&command)
+        }
+        self.static_credentials(name)
+    }
+
+    /// Access keys written in profile `name`
+    fn static_credentials(&self, name: &str) -> Result<Expiring> {
+        let get = |key| self.get(name, key).map(String::from);
+        match (get("aws_access_key_id"), get("aws_secret_access_key")) {
+            (Some(access_key), Some(secret_key)) =>
+                Ok(credentials(access_key, secret_key, get("aws_session_token"))),
+            _ => Err(Error::msg(format!("Profile \"{}\" has no credentials", name))),
+        }
+    }
+}
+
+/// Load an AWS config file, taking values as written
+fn load_ini(path: &str) -> Result<Ini> {
+    let path = expand_home(path);
+    let options = ParseOption { enabled_quote: false, enabled_escape: false };
+    match Ini::load_from_file_opt(&path, options) {
+        Ok(ini) => Ok(ini),
+        Err(ini::Error::Io(e)) if e.kind() == io::ErrorKind::NotFound => {
+            trace!("No AWS config file at {:?}", path);
+            Ok(Ini::new())
+        },
+        Err(e) => Err(Error::msg(format!("Unable to read AWS config file {:?}: {}", path, e))),
+    }
+}
+
+/// Run a profile's `credential_process`, which prints credentials as JSON
+fn credential_process(command: &str) -> Result<Expiring> {
+    debug!("Running credential_process");
+    let mut shell = if cfg!(windows) {
+        let mut shell = Command::new("cmd"); shell.arg("/C"); shell
+    } else {
+        let mut shell = Command::new("sh"); shell.arg("-c"); shell
+    };
+    // Our stdin and stdout belong to git
+    let output = shell.arg(command)
+        .stdin(Stdio::null())
+        .stderr(Stdio::inherit())
+        .output()
+        .with_context(|| format!("Unable to run credential_process \"{}\"", command))?;
+    if !output.status.success() {
+        return Err(Error::msg(format!("credential_process \"{}\" failed: {}", command, output.status)))
+    }
+    let response: TemporaryCredentials = serde_json::from_slice(&output.stdout)
+        .with_context(|| format!("Invalid output from credential_process \"{}\"", command))?;
+    Ok(response.into())
+}
+
+/// Credentials as STS, the metadata services and credential processes return them
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct TemporaryCredentials {
+    access_key_id: String,
+    secret_access_key: String,
+    #[serde(alias = "Token")]
+    session_token: Option<String>,
+    expiration: Option<String>,
+}
+
+impl From<TemporaryCredentials> for Expiring {
+    fn from(temporary: TemporaryCredentials) -> Self {
+        debug!("Credentials expire at {}", temporary.expiration.as_deref().unwrap_or("never"));
+        // Ones whose expiration can't be read are used until requests with them fail
+        let expiration = temporary.expiration.as_deref()
+            .and_then(|expiration| DateTime::parse_from_rfc3339(expiration).ok())
+            .map(|expiration| expiration.with_timezone(&Utc));
+        Expiring {
+            expiration,
+            ..credentials(temporary.access_key_id, temporary.secret_access_key, temporary.session_token)
+        }
+    }
+}
+
+/// Credentials from the container credentials endpoint, as ECS and EKS pod identity provide
+fn from_container() -> Result<Option<Expiring>> {
+    let url = match (var("AWS_CONTAINER_CREDENTIALS_RELATIVE_URI"), var("AWS_CONTAINER_CREDENTIALS_FULL_URI")) {
+        (Some(path), _) => format!("http://169.254.170.2{}", path),
+        (None, Some(url)) => url,
+        (None, None) => return Ok(None),
+    };
+    let token = match (var("AWS_CONTAINER_AUTHORIZATION_TOKEN_FILE"), var("AWS_CONTAINER_AUTHORIZATION_TOKEN")) {
+        (Some(path), _) => Some(read_token(&path)?),
+        (None, token) => token,
+    };
+    let mut request = attohttpc::get(&url).timeout(METADATA_TIMEOUT);
+    if let Some(token) = token {
+        request = request.header("Authorization", token);
+    }
+    let response = request.send()
+        .with_context(|| format!("Unable to reach container credentials at {}", url))?;
+    let response: TemporaryCredentials = success(response, "container credentials")?.json()
+        .context("Invalid container credentials")?;
+    Ok(Some(response.into()))
+}
+
+/// Credentials of the instance's role from the instance metadata service, `None` if there is
+/// no such service or role. Sessions (IMDSv2) are used if the service has them
+fn from_instance_metadata() -> Result<Option<Expiring>> {
+    if var("AWS_EC2_METADATA_DISABLED").is_some_and(|value| value.eq_ignore_ascii_case("true")) {
+        return Ok(None)
+    }
+    let endpoint = var("AWS_EC2_METADATA_SERVICE_ENDPOINT")
+        .unwrap_or_else(|| METADATA_ENDPOINT.to_string());
+    let endpoint = endpoint.trim_end_matches('/');
+    let token = match attohttpc::put(format!("{}/latest/api/token", endpoint))
+        .header("X-aws-ec2-metadata-token-ttl-seconds", "21600")
+        .timeout(METADATA_TIMEOUT)
+        .send() {
+        Ok(response) if response.is_success() => Some(response.text()?),
+        // Only IMDSv1
+        Ok(response) => {
+            debug!("No instance metadata session: {}", response.status());
+            None
+        },
+        Err(e) => {
+            debug!("No instance metadata service at {}: {}", endpoint, e);
+            return Ok(None)
+        },
+    };
+    let get = |path: &str| {
+        let url = format!("{}/latest/meta-data/iam/security-credentials/{}", endpoint, path);
+        let mut request = attohttpc::get(&url).timeout(METADATA_TIMEOUT);
+        if let Some(token) = &token {
+            request = request.header("X-aws-ec2-metadata-token", token.as_str());
+        }
+        request.send().with_context(|| format!("Unable to fetch {}", url))
+    };
+
+    let response = get("")?;
+    if response.status() == attohttpc::StatusCode::NOT_FOUND {
+        debug!("Instance has no role");
+        return Ok(None)
+    }
+    let roles = success(response, "instance metadata")?.text()?;
+    let role = match roles.lines().next().map(str::trim).filter(|role| !role.is_empty()) {
+        Some(role) => role,
+        None => return Ok(None),
+    };
+    debug!("Instance role is \"{}\"", role);
+    let response: TemporaryCredentials = success(get(role)?, "instance metadata")?.json()
+        .context("Invalid instance metadata credentials")?;
+    Ok(Some(response.into()))
+}
+
+/// `response` if it succeeded, otherwise an error with its status and body
+fn success(response: attohttpc::Response, service: &str) -> Result<attohttpc::Response> {
+    if response.is_success() {
+        return Ok(response)
+    }
+    let status = response.status();
+    let body = response.text().unwrap_or_default();
+    Err(Error::msg(format!("Non-okay response from {}: {} {}", service, status, body.trim())))
+}
+
+/// A role to assume
+struct Role {
+    arn: String,
+    session_name: Option<String>,
+    external_id: Option<String>,
+    duration_seconds: Option<String>,
+}
+
+impl Role {
+    /// Parameters of a request to assume the role
+    fn params(&self) -> Vec<(&str, &str)> {
+        let mut params = vec![
+            ("RoleArn", self.arn.as_str()),
+            ("RoleSessionName", self.session_name.as_deref().unwrap_or(SESSION_NAME)),
+            ("Version", "2011-06-15"),
+        ];
+        if let Some(external_id) = &self.external_id {
+            params.push(("ExternalId", external_id));
+        }
+        if let Some(duration) = &self.duration_seconds {
+            params.push(("DurationSeconds", duration));
+        }
+        params
+    }
+}
+
+/// The security token service, which hands out temporary credentials for roles
+struct Sts {
+    endpoint: String,
+    region: String,
+}
+
+impl Sts {
+    /// STS in `region`, or the global endpoint. `AWS_ENDPOINT_URL_STS` overrides where requests
+    /// go, as for other AWS tools
+    fn new(region: Option<String>) -> Sts {
+        let endpoint = match (var("AWS_ENDPOINT_URL_STS"), &region) {
+            (Some(endpoint), _) => endpoint,
+            (None, Some(region)) => format!("https://sts.{}.amazonaws.com", region),
+            (None, None) => "https://sts.amazonaws.com".to_string(),
+        };
+        Sts { endpoint, region: region.unwrap_or_else(|| "us-east-1".to_string()) }
+    }
+
+    /// Assume `role` with the credentials of its source
+    fn assume_role(&self, role: &Role, source: &Expiring) -> Result<Expiring> {
+        let mut params = role.params();
+        params.push(("Action", "AssumeRole"));
+        self.call(&params, Some(&source.credentials))
+            .with_context(|| format!("Unable to assume role {}", role.arn))
+    }
+
+    /// Assume `role` with a web identity token, as Kubernetes service accounts get
+    fn assume_role_with_web_identity(&self, role: &Role, token: &str) -> Result<Expiring> {
+        let mut params = role.params();
+        params.push(("Action", "AssumeRoleWithWebIdentity"));
+        params.push(("WebIdentityToken", token));
+        self.call(&params, None)
+            .with_context(|| format!("Unable to assume role {} with a web identity", role.arn))
+    }
+
+    /// Make a request with `params`, signed with `signer` if set, for temporary credentials
+    fn call(&self, params: &[(&str, &str)], signer: Option<&Credentials>) -> Result<Expiring> {
+        let url = Url::parse(&self.endpoint)
+            .with_context(|| format!("Invalid STS endpoint {}", self.endpoint))?;
+        let host = match (url.host_str(), url.port()) {
+            (Some(host), Some(port)) => format!("{}:{}", host, port),
+            (Some(host), None) => host.to_string(),
+            (None, _) => return Err(Error::msg(format!("No host in STS endpoint {}", self.endpoint))),
+        };
+        let body = params.iter()
+            .map(|(key, value)| format!("{}={}", key, uri_encode(value, true)))
+            .collect::<Vec<_>>()
+            .join("&");
+        debug!("Requesting credentials from {}", url);
+
+        let content_type = "application/x-www-form-urlencoded; charset=utf-8";
+        let mut request = attohttpc::post(url.as_str())
+            .header("Content-Type", content_type)
+            .timeout(Duration::from_secs(30));
+        if let Some(signer) = signer {
+            let date = Utc::now().format("%Y%m%dT%H%M%SZ").to_string();
+            let mut headers = vec![
+                ("content-type", content_type), ("host", &host), ("x-amz-date", &date),
+            ];
+            if let Some(token) = &signer.session_token {
+                headers.push(("x-amz-security-token", token));
+            }
+            let signing = Signing {
+                access_key: signer.access_key.as_deref().unwrap_or_default(),
+                secret_key: signer.secret_key.as_deref().unwrap_or_default(),
+                region: &self.region,
+                service: "sts",
+            };
+            let authorization = signing.authorization(
+                "POST", url.path(), "", &headers, body.as_bytes(),
+            );
+            for (name, value) in headers.into_iter().filter(|(name, _)| *name != "host") {
+                request = request.header(name, value);
+            }
+            request = request.header("Authorization", authorization);
+        }
+        let response = request.bytes(body).send()
+            .with_context(|| format!("Unable to reach STS at {}", url))?;
+        let status = response.status();
+        let text = response.text()?;
+        if !status.is_success() {
+            let message = serde_xml_rs::from_str::<StsErrorResponse>(&text)
+                .map(|e| format!("{}: {}", e.error.code, e.error.message))
+                .unwrap_or_else(|_| text.trim().to_string());
+            return Err(Error::msg(format!("Non-okay response from STS: {} {}", status, message)))
+        }
+        let response: StsResponse = serde_xml_rs::from_str(&text)
+            .context("Invalid response from STS")?;
+        Ok(response.result.credentials.into())
+    }
+}
+
+/// Response to `AssumeRole` or `AssumeRoleWithWebIdentity`
+#[derive(Debug, Deserialize)]
+struct StsResponse {
+    #[serde(rename = "AssumeRoleResult", alias = "AssumeRoleWithWebIdentityResult")]
+    result: StsResult,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct StsResult {
+    credentials: TemporaryCredentials,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct StsErrorResponse {
+    error: StsError,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct StsError {
+    code: String,
+    message: String,
+}
+
+/// Signs requests to AWS services with Signature Version 4
+struct Signing<'a> {
+    access_key: &'a str,
+    secret_key: &'a str,
+    region: &'a str,
+    service: &'a str,
+}
+
+impl Signing<'_> {
+    /// `Authorization` header of a request. All of `headers` are signed, and they must include
+    /// `host` and `x-amz-date`, with lowercase names
+    fn authorization(&self, method: &str, path: &str, query: &str, headers: &[(&str, &str)], body: &[u8]) -> String {
+        let mut headers = headers.to_vec();
+        headers.sort();
+        let date = headers.iter()
+            .find(|(name, _)| *name == "x-amz-date")
+            .map_or("", |(_, value)| *value);
+        let canonical_headers: String = headers.iter()
+            .map(|(name, value)| format!("{}:{}\n", name, value.trim()))
+            .collect();
+        let signed_headers = headers.iter()
+            .map(|(name, _)| *name)
+            .collect::<Vec<_>>()
+            .join(";");
+        let canonical_request = format!(
+            "{}\n{}\n{}\n{}\n{}\n{}",
+            method, path, query, canonical_headers, signed_headers, hex::encode(Sha256::digest(body)),
+        );
+        trace!("Canonical request is {:?}", canonical_request);
+
+        let day = &date[..date.len().min(8)];
+        let scope = format!("{}/{}/{}/aws4_request", day, self.region, self.service);
+        let string_to_sign = format!(
+            "AWS4-HMAC-SHA256\n{}\n{}\n{}",
+            date, scope, hex::encode(Sha256::digest(canonical_request.as_bytes())),
+        );
+        let key = [day, self.region, self.service, "aws4_request"].iter()
+            .fold(format!("AWS4{}", self.secret_key).into_bytes(), |key, part| hmac(&key, part.as_bytes()));
+        format!(
+            "AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders={}, Signature={}",
+            self.access_key, scope, signed_headers, hex::encode(hmac(&key, string_to_sign.as_bytes())),
+        )
+    }
+}
+
+/// HMAC-SHA256 of `data` under `key`
+fn hmac(key: &[u8], data: &[u8]) -> Vec<u8> {
+    let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(key)
+        .expect("HMAC takes keys of any size");
+    mac.update(data);
+    mac.finalize().into_bytes().to_vec()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_signing() {
+        // Example from the AWS Signature Version 4 documentation
+        let signing = Signing {
+            access_key: "AKIDEXAMPLE",
+            secret_key: "wJalrXUtnFEMI/K7MDENG+bPxRfiCYEXAMPLEKEY",
+            region: "us-east-1",
+            service: "iam",
+        };
+        let headers = [
+            ("content-type", "application/x-www-form-urlencoded; charset=utf-8"),
+            ("host", "iam.amazonaws.com"),
+            ("x-amz-date", "20150830T123600Z"),
+        ];
+        assert_eq!(signing.authorization("GET", "/", "Action=ListUsers&Version=2010-05-08", &headers, b""),
+        "AWS4-HMAC-SHA256 Credential=AKIDEXAMPLE/20150830/us-east-1/iam/aws4_request, \
+            SignedHeaders=content-type;host;x-amz-date, \
+            Signature=5d672d79c15b13162d9279b0855cfba6789a8edb4c82c400e06b5924a6f2b5d7");
+    }
+    #[test]
+    fn test_profiles() {
+        let options = || ParseOption { enabled_quote: false, enabled_escape: false };
+        let files = AwsFiles {
+            credentials: Ini::load_from_str_opt("[keys]\n\
+                aws_access_key_id = AKID\n\
+                aws_secret_access_key = secret\n\
+                aws_session_token = token\n", options()).unwrap(),
+            config: Ini::load_from_str_opt("[profile process]\n\
+                credential_process = echo '{\"Version\": 1, \"AccessKeyId\": \"PROC\", \"SecretAccessKey\": \"s\"}'\n\
+                [profile loop]\n\
+                role_arn = arn:aws:iam::123456789012:role/r\n\
+                source_profile = loop2\n\
+                [profile loop2]\n\
+                role_arn = arn:aws:iam::123456789012:role/r\n\
+                source_profile = loop\n\
+                [profile sourceless]\n\
+                role_arn = arn:aws:iam::123456789012:role/r\n", options()).unwrap(),
+        };
+        let keys = files.profile_credentials("keys", 0).unwrap();
+        assert_eq!(keys, credentials("AKID".to_string(), "secret".to_string(), Some("token".to_string())));
+        #[cfg(unix)]
+        assert_eq!(files.profile_credentials("process", 0).unwrap(),
+        credentials("PROC".to_string(), "s".to_string(), None));
+        assert!(files.profile_credentials("loop", 0).is_err());
+        assert!(files.profile_credentials("sourceless", 0).is_err());
+        assert!(files.profile_credentials("missing", 0).is_err());
+    }
+    /// AssumeRoleWithWebIdentity response, as STS sends it
+    const WEB_IDENTITY_RESPONSE: &str = r#"
+        <AssumeRoleWithWebIdentityResponse xmlns="https://sts.amazonaws.com/doc/2011-06-15/">
+          <AssumeRoleWithWebIdentityResult>
+            <SubjectFromWebIdentityToken>system:serviceaccount:ci:runner</SubjectFromWebIdentityToken>
+            <Credentials>
+              <SessionToken>token</SessionToken>
+              <SecretAccessKey>secret</SecretAccessKey>
+              <Expiration>2026-10-18T12:00:00Z</Expiration>
+              <AccessKeyId>ASIA</AccessKeyId>
+            </Credentials>
+          </AssumeRoleWithWebIdentityResult>
+        </AssumeRoleWithWebIdentityResponse>"#;
+
+    fn web_identity_credentials() -> Expiring {
+        let expiration = DateTime::parse_from_rfc3339("2026-10-18T12:00:00Z").unwrap().with_timezone(&Utc);
+        Expiring {
+            expiration: Some(expiration),
+            ..credentials("ASIA".to_string(), "secret".to_string(), Some("token".to_string()))
+        }
+    }
+    #[test]
+    fn test_sts_response() {
+        let response: StsResponse = serde_xml_rs::from_str(WEB_IDENTITY_RESPONSE).unwrap();
+        assert_eq!(Expiring::from(response.result.credentials), web_identity_credentials());
+    }
+    #[test]
+    fn test_assume_role_with_web_identity() {
+        use std::io::{BufRead, BufReader, Read, Write};
+        use std::net::TcpListener;
+        // Stands in for STS, answering a single request
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let endpoint = format!("http://{}", listener.local_addr().unwrap());
+        let server = std::thread::spawn(move || {
+            let mut stream = BufReader::new(listener.accept().unwrap().0);
+            let mut length = 0;
+            loop {
+                let mut line = String::new();
+                stream.read_line(&mut line).unwrap();
+                match line.split_once(':') {
+                    Some((name, value)) if name.eq_ignore_ascii_case("content-length") =>
+                        length = value.trim().parse().unwrap(),
+                    _ if line.trim().is_empty() => break,
+                    _ => (),
+                }
+            }
+            let mut body = vec![0; length];
+            stream.read_exact(&mut body).unwrap();
+            write!(stream.get_mut(), "HTTP/1.1 200 OK\r\nContent-Type: text/xml\r\nContent-Length: {}\r\n\
+                Connection: close\r\n\r\n{}", WEB_IDENTITY_RESPONSE.len(), WEB_IDENTITY_RESPONSE).unwrap();
+            String::from_utf8(body).unwrap()
+        });
+
+        let sts = Sts { endpoint, region: "us-east-1".to_string() };
+        let role = Role {
+            arn: "arn:aws:iam::123456789012:role/ci".to_string(),
+            session_name: None,
+            external_id: None,
+            duration_seconds: None,
+        };
+        assert_eq!(sts.assume_role_with_web_identity(&role, "jwt").unwrap(), web_identity_credentials());
+        let body = server.join().unwrap();
+        assert!(body.contains("Action=AssumeRoleWithWebIdentity"));
+        assert!(body.contains("WebIdentityToken=jwt"));
+        assert!(body.contains("RoleArn=arn%3Aaws%3Aiam%3A%3A123456789012%3Arole%2Fci"));
+        assert!(body.contains("RoleSessionName=git-remote-s3"));
+    }
+    #[test]
+    fn test_expires_soon() {
+        assert!(!expires_soon(None));
+        assert!(!expires_soon(Some(Utc::now() + Age::hours(1))));
+        assert!(expires_soon(Some(Utc::now() + Age::minutes(1))));
+        assert!(expires_soon(Some(Utc::now() - Age::minutes(1))));
+    }
+}
@@ -0,0 +1,267 @@
+use super::remote::Remote;
+
+use anyhow::{Context, Error, Result};
+use std::convert::TryFrom;
+use std::fs;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::Path;
+
+use aes_gcm::aead::{Aead, KeyInit, Payload};
+use aes_gcm::{Aes256Gcm, Nonce};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+/// Magic bytes starting everything encrypted, followed by the nonce prefix
+const MAGIC: &[u8] = b"GITS3EC1";
+
+/// Random bytes starting the nonce of every chunk of an object
+const NONCE_PREFIX_SIZE: usize = 7;
+
+/// Size of the plaintext sealed in each chunk, so objects of any size can be streamed
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// Size of the authentication tag following each chunk
+const TAG_SIZE: usize = 16;
+
+/// Encrypts what is stored in the bucket with AES-256-GCM. Data is split into chunks sealed in
+/// order, each nonce being a random prefix, the chunk's number and whether it is the last (the
+/// STREAM construction), so chunks can't be reordered or dropped. Every chunk is bound to the key
+/// it is stored under, so objects can't be swapped around either
+pub struct Cipher {
+    aead: Aes256Gcm,
+    /// Key names are derived with, kept apart from the one encrypting data
+    name_key: Vec<u8>,
+}
+
+impl Cipher {
+    /// Create a cipher from a 256 bit key written as 64 hex characters
+    pub fn from_hex(key: &str) -> Result<Self> {
+        let key = hex::decode(key.trim())
+            .context("Encryption key is not hex")?;
+        if key.len() != 32 {
+            return Err(Error::msg(format!("Encryption key is {} bytes, expected 32", key.len())))
+        }
+        Ok(Cipher {
+            aead: Aes256Gcm::new_from_slice(&derive(&key, b"git-remote-s3 data"))
+                .map_err(|_| Error::msg("Invalid encryption key"))?,
+            name_key: derive(&key, b"git-remote-s3 names"),
+        })
+    }
+
+    /// Name to store `name` under, which can't be traced back to it without the key
+    pub fn name(&self, name: &str) -> String {
+        hex::encode(derive(&self.name_key, name.as_bytes()))
+    }
+
+    /// Encrypt everything read from `input` into `output`, to be stored under `key`
+    pub fn encrypt<R: Read, W: Write>(&self, key: &str, mut input: R, mut output: W) -> Result<W> {
+        let mut prefix = [0; NONCE_PREFIX_SIZE];
+        getrandom::getrandom(&mut prefix)
+            .map_err(|e| Error::msg(format!("Unable to generate nonce: {}", e)))?;
+        output.write_all(MAGIC)?;
+        output.write_all(&prefix)?;
+
+        let mut chunk = vec![0; CHUNK_SIZE];
+        let mut next = vec![0; CHUNK_SIZE];
+        let mut len = read_full(&mut input, &mut chunk)?;
+        let mut counter = 0;
+        loop {
+            // A full chunk is only the last if nothing follows it
+            let next_len = if len == CHUNK_SIZE { read_full(&mut input, &mut next)? } else { 0 };
+            let last = next_len == 0;
+            let payload = Payload { msg: &chunk[..len], aad: key.as_bytes() };
+            let sealed = self.aead.encrypt(&nonce(&prefix, counter, last)?, payload)
+                .map_err(|_| Error::msg(format!("Unable to encrypt \'{}\'", key)))?;
+            output.write_all(&sealed)?;
+            if last {
+                return Ok(output)
+            }
+            std::mem::swap(&mut chunk, &mut next);
+            len = next_len;
+            counter += 1;
+        }
+    }
+
+    /// Decrypt everything read from `input`, as stored under `key`, into `output`. Fails if
+    /// anything was changed, truncated, or moved from another key
+    pub fn decrypt<R: Read, W: Write>(&self, key: &str, mut input: R, mut output: W) -> Result<W> {
+        let mut header = vec![0; MAGIC.len() + NONCE_PREFIX_SIZE];
+        let header_len = read_full(&mut input, &mut header)?;
+        if !header[..header_len].starts_with(MAGIC) || header_len < header.len() {
+            return Err(Error::msg(format!("\'{}\' is not encrypted, but an encryption key is set", key)))
+        }
+        let prefix = &header[MAGIC.len()..];
+
+        let mut chunk = vec![0; CHUNK_SIZE + TAG_SIZE];
+        let mut next = vec![0; CHUNK_SIZE + TAG_SIZE];
+        let mut len = read_full(&mut input, &mut chunk)?;
+        let mut counter = 0;
+        loop {
+            let next_len = if len == chunk.len() { read_full(&mut input, &mut next)? } else { 0 };
+            let last = next_len == 0;
+            let payload = Payload { msg: &chunk[..len], aad: key.as_bytes() };
+            let opened = self.aead.decrypt(&nonce(prefix, counter, last)?, payload)
+                .map_err(|_| Error::msg(format!("Unable to decrypt \'{}\', it was changed or the key is wrong", key)))?;
+            output.write_all(&opened)?;
+            if last {
+                return Ok(output)
+            }
+            std::mem::swap(&mut chunk, &mut next);
+            len = next_len;
+            counter += 1;
+        }
+    }
+}
+
+impl Remote {
+    /// Encrypt `data` to be stored under `key`, if an encryption key is set
+    pub fn seal(&self, key: &str, data: &[u8]) -> Result<Vec<u8>> {
+        match &self.cipher {
+            Some(cipher) => cipher.encrypt(key, data, Vec::new()),
+            None => Ok(data.to_vec()),
+        }
+    }
+
+    /// Decrypt `data` read from `key`, if an encryption key is set
+    pub fn unseal(&self, key: &str, data: Vec<u8>) -> Result<Vec<u8>> {
+        match &self.cipher {
+            Some(cipher) => cipher.decrypt(key, data.as_slice(), Vec::new()),
+            None if data.starts_with(MAGIC) => Err(not_readable(key)),
+            None => Ok(data),
+        }
+    }
+
+    /// Encrypt the file at `src` into `dst`, to be stored under `key`. Only to be called with an
+    /// encryption key set
+    pub fn seal_file(&self, key: &str, src: &Path, dst: &Path) -> Result<()> {
+        let cipher = self.cipher.as_ref().expect("sealing a file needs an encryption key");
+        let input = fs::File::open(src)
+            .with_context(|| format!("Unable to open {:?}", src))?;
+        let output = fs::File::create(dst)
+            .with_context(|| format!("Unable to create {:?}", dst))?;
+        cipher.encrypt(key, BufReader::new(input), BufWriter::new(output))?
+            .flush()
+            .with_context(|| format!("Unable to write {:?}", dst))
+    }
+
+    /// Decrypt the file at `src`, read from `key`, into `dst`. Without an encryption key it is
+    /// moved as is. `src` is gone afterwards either way
+    pub fn unseal_file(&self, key: &str, src: &Path, dst: &Path) -> Result<()> {
+        let result = (|| {
+            let mut input = fs::File::open(src)
+                .with_context(|| format!("Unable to open {:?}", src))?;
+            match &self.cipher {
+                Some(cipher) => {
+                    let output = fs::File::create(dst)
+                        .with_context(|| format!("Unable to create {:?}", dst))?;
+                    cipher.decrypt(key, BufReader::new(input), BufWriter::new(output))?
+                        .flush()
+                        .with_context(|| format!("Unable to write {:?}", dst))
+                },
+                None => {
+                    let mut magic = Vec::new();
+                    (&mut input).take(MAGIC.len() as u64).read_to_end(&mut magic)?;
+                    if magic == MAGIC {
+                        return Err(not_readable(key))
+                    }
+                    fs::rename(src, dst)
+                        .with_context(|| format!("Unable to move {:?} to {:?}", src, dst))
+                },
+            }
+        })();
+        fs::remove_file(src).ok();
+        // Never leave anything unauthenticated behind
+        if result.is_err() {
+            fs::remove_file(dst).ok();
+        }
+        result
+    }
+}
+
+/// Error for an encrypted object read without a key
+fn not_readable(key: &str) -> Error {
+    Error::msg(format!("\'{}\' is encrypted, set s3.encryptionKey or s3.encryptionKeyFile to read it", key))
+}
+
+/// HMAC-SHA256 of `data` under `key`
+fn derive(key: &[u8], data: &[u8]) -> Vec<u8> {
+    let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(key)
+        .expect("HMAC takes keys of any size");
+    mac.update(data);
+    mac.finalize().into_bytes().to_vec()
+}
+
+/// Nonce of chunk number `counter` of an object
+fn nonce(prefix: &[u8], counter: u64, last: bool) -> Result<Nonce<aes_gcm::aead::consts::U12>> {
+    let counter = u32::try_from(counter)
+        .map_err(|_| Error::msg("Too much data to encrypt as one object"))?;
+    let mut nonce = prefix.to_vec();
+    nonce.extend_from_slice(&counter.to_be_bytes());
+    nonce.push(last as u8);
+    Ok(*Nonce::from_slice(&nonce))
+}
+
+/// Read until `buf` is full or `input` ends, returning how much was read
+fn read_full<R: Read>(input: &mut R, buf: &mut [u8]) -> io::Result<usize> {
+    let mut len = 0;
+    while len < buf.len() {
+        match input.read(&mut buf[len..]) {
+            Ok(0) => break,
+            Ok(n) => len += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => (),
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(len)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cipher() -> Cipher {
+        Cipher::from_hex(&"2a".repeat(32)).unwrap()
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let cipher = cipher();
+        // Empty, partial, exactly one chunk, and several chunks
+        for len in &[0, 100, CHUNK_SIZE, 3 * CHUNK_SIZE + 5] {
+            let data: Vec<u8> = (0..*len).map(|i| i as u8).collect();
+            let sealed = cipher.encrypt("refs/heads/main", data.as_slice(), Vec::new()).unwrap();
+            assert!(sealed.starts_with(MAGIC));
+            assert_eq!(cipher.decrypt("refs/heads/main", sealed.as_slice(), Vec::new()).unwrap(), data);
+        }
+    }
+    #[test]
+    fn test_tampering_detected() {
+        let cipher = cipher();
+        let data = vec![7; 2 * CHUNK_SIZE];
+        let sealed = cipher.encrypt("packs/a.pack", data.as_slice(), Vec::new()).unwrap();
+        // Moved to another key
+        assert!(cipher.decrypt("packs/b.pack", sealed.as_slice(), Vec::new()).is_err());
+        // Truncated at a chunk boundary
+        let truncated = &sealed[..MAGIC.len() + NONCE_PREFIX_SIZE + CHUNK_SIZE + TAG_SIZE];
+        assert!(cipher.decrypt("packs/a.pack", truncated, Vec::new()).is_err());
+        // Changed
+        let mut changed = sealed.clone();
+        changed[40] ^= 1;
+        assert!(cipher.decrypt("packs/a.pack", changed.as_slice(), Vec::new()).is_err());
+        // Wrong key
+        let other = Cipher::from_hex(&"2b".repeat(32)).unwrap();
+        assert!(other.decrypt("packs/a.pack", sealed.as_slice(), Vec::new()).is_err());
+        // Not encrypted
+        assert!(cipher.decrypt("packs/a.pack", &b"PACK"[..], Vec::new()).is_err());
+    }
+    #[test]
+    fn test_names() {
+        let cipher = cipher();
+        let name = cipher.name("pack-0123");
+        assert_eq!(name.len(), 64);
+        assert_eq!(name, cipher.name("pack-0123"));
+        assert_ne!(name, cipher.name("pack-0124"));
+        assert!(Cipher::from_hex("abcd").is_err());
+        assert!(Cipher::from_hex("not hex").is_err());
+    }
+}
@@ -1,10 +1,16 @@
 use super::remote::Remote;
+use super::util::{decode_loose_object, object_references, parallel_map};
 
-use log::{trace, debug};
+use log::{info, trace, debug};
 use anyhow::{Context, Error, Result};
 use git_object::Kind;
-use git_object::immutable::{Commit, Tree};
 use git_hash::ObjectId;
+use git_odb::pack;
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::Path;
+use futures::stream::{FuturesUnordered, StreamExt};
 
 impl Remote {
     /*
@@ -17,95 +23,349 @@ impl Remote {
      *
      * Needed by fetch
      */
-    /// This is a mess of copys and string passing for what should be byte arrays. I have no idea
-    /// how to clean it up at the moment
-    pub fn fetch(&self, sha1: &str) -> Result<()> {
-        // Fetch commit
-        self.fetch_commit(sha1)
-    }
-    /// Fetch a commit, and all objects it depends on
-    fn fetch_commit(&self, sha1: &str) -> Result<()> {
-        let data: Vec<u8> = match self.fetch_object(sha1.to_string(), Kind::Commit)
-            .with_context(|| format!("Unable to fetch commit \'{}\'", &sha1))? {
-            Some(d) => d,
-            // If we returned ok but w/ empty data the object already exists. Exit
-            None => return Ok(()),
-        };
+    /// Fetch a whole batch of commits and tags. Remote packs are searched once for the entire
+    /// batch, so a pack several refs need is only downloaded once. Returns whether everything
+    /// fetched was checked to be connected, as git asks for with `check-connectivity`
+    pub async fn fetch(&mut self, sha1s: &[&str]) -> Result<bool> {
+        let ids = sha1s.iter()
+            .map(|sha1| ObjectId::from_hex(sha1.as_bytes())
+                .with_context(|| format!("Unable to load commit \'{}\' into ObjectId", sha1)))
+            .collect::<Result<Vec<_>>>()?;
 
-        trace!("{} was a commit. Parsing", sha1);
-        // Parse object, fetch deps
-        let commit_obj = Commit::from_bytes(&data)?;
-        trace!("Searching for children of {}", sha1);
-        self.fetch_tree(std::str::from_utf8(&commit_obj.tree().to_sha1_hex())?)
-            .with_context(|| format!("Unable to fetch tree for commit \'{}\'", &sha1))?;
-        commit_obj.parents()
-            .try_for_each(|obj| self.fetch_commit(std::str::from_utf8(&obj.to_sha1_hex())?))
-            .with_context(|| format!("Unable to fetch parent for commit \'{}\'", &sha1))?;
-        Ok(())
-    }
-    /// Fetch a tree recursively
-    fn fetch_tree(&self, sha1: &str) -> Result<()> {
-        let data: Vec<u8> = match self.fetch_object(sha1.to_string(), Kind::Tree)
-            .with_context(|| format!("Unable to fetch tree \'{}\'", sha1))? {
-            Some(d) => d,
-            // If we returned ok but w/ empty data the object already exists. Exit
-            None => return Ok(()),
-        };
+        // Pull in every remote pack the refs need. Anything not in a pack was pushed by an older
+        // version as a loose object, which can be of any kind
+        let loose = self.fetch_packs(ids.clone()).await
+            .context("Unable to fetch packs")?;
+        self.fetch_loose(loose).await?;
 
-        trace!("{} was a tree. Parsing", sha1);
-        // Parse tree, fetch deps
-        let tree_obj = Tree::from_bytes(&data)?;
-        trace!("Searching for children of {}", sha1);
-        // Iter over entries, fetch tree or object
-        tree_obj.entries.iter()
-            .try_for_each(|e| {
-                 let sha1_bytes = e.oid.to_sha1_hex();
-                 let sha1 = std::str::from_utf8(&sha1_bytes)
-                     .context("Unable to parse sha from child of tree")?;
-                 if e.mode.is_tree() {
-                     self.fetch_tree(sha1)
-                 } else {
-                     match self.fetch_object(sha1.to_string(), Kind::Blob) {
-                         Ok(_) => Ok(()),
-                         Err(error) => Err(error),
-                     }
-                 }
-            })
-            .with_context(|| format!("Unable to fetch entries for tree \'{}\'", &sha1))?;
+        if self.options.check_connectivity {
+            for id in ids {
+                self.check_connectivity(id)
+                    .with_context(|| format!("Connectivity check failed for \'{}\'", id))?;
+            }
+        }
+        Ok(self.options.check_connectivity)
+    }
+    /// Verify every object reachable from commit `id` exists in the local database
+    fn check_connectivity(&self, id: ObjectId) -> Result<()> {
+        debug!("Checking connectivity of {}", id);
+        let mut seen = HashSet::new();
+        let mut queue = vec![(id, Kind::Commit)];
+        let mut buf = Vec::new();
+        while let Some((id, kind)) = queue.pop() {
+            if !seen.insert(id) {
+                continue
+            }
+            // Blobs reference nothing, no need to read them
+            if kind == Kind::Blob {
+                if !self.has_object(&id) {
+                    return Err(Error::msg(format!("Object {} is missing", id)))
+                }
+                continue
+            }
+            let obj = self.git_db.find(id, &mut buf, &mut pack::cache::Never)
+                .context("Error found searching db")?
+                .ok_or_else(|| Error::msg(format!("Object {} is missing", id)))?;
+            queue.extend(object_references(obj.kind, obj.data)?);
+        }
         Ok(())
     }
-    /// Fetch an object from remote by SHA, save to local git object store.
-    /// Blocks
-    fn fetch_object(&self, sha1: String, obj_type: Kind) -> Result<Option<Vec<u8>>> {
-        trace!("Fetching object {}", sha1);
-
-        // Build oid
-        let id = ObjectId::from_hex(sha1.as_bytes()).context("Unable to load tree into ObjectId")?;
+    /// Download every remote pack holding a wanted object, or an object those reference, into the
+    /// local object database. Returns the objects not found in any remote pack
+    async fn fetch_packs(&mut self, wanted: Vec<ObjectId>) -> Result<Vec<ObjectId>> {
+        let (bundles, not_packed) = self.download_packs(wanted).await?;
+        self.git_db.packs.extend(bundles);
+        debug!("{} objects not found in remote packs", not_packed.len());
+        Ok(not_packed)
+    }
+    /// Walk from the wanted objects through the remote pack indexes, starting the download of a
+    /// pack as soon as one of its objects is needed. The walk carries on through each pack once
+    /// it is downloaded. Returns the downloaded packs, and the objects in none of them
+    async fn download_packs(&self, wanted: Vec<ObjectId>) -> Result<(Vec<pack::Bundle>, Vec<ObjectId>)> {
+        let names = self.list_remote_packs().await?;
+        let indexes = parallel_map(names, self.parallelism, |name| async move {
+            let index = self.remote_pack_index(&name).await?;
+            Ok((name, index))
+        }).await?;
+        // The pack each remote object is in. Names are taken once their pack is needed
+        let mut packs = HashMap::new();
+        for (pos, (_, index)) in indexes.iter().enumerate() {
+            for entry in index.iter() {
+                packs.entry(entry.oid).or_insert(pos);
+            }
+        }
+        let mut names: Vec<Option<String>> = indexes.into_iter().map(|(name, _)| Some(name)).collect();
 
-        // Check ref if already exists, return None if true
+        let download = |name: String| async move {
+            let bundle = self.download_pack(&name).await?;
+            Ok::<_, Error>((name, bundle))
+        };
+        let mut in_flight = FuturesUnordered::new();
+        // Packs needed, waiting for a download slot
+        let mut to_download = Vec::new();
+        let mut bundles = Vec::new();
+        let mut not_packed = Vec::new();
+        let mut seen = HashSet::new();
+        let mut queue = wanted;
         let mut buf = Vec::new();
-        if self.git_db.find(id, &mut buf, &mut git_odb::pack::cache::Never)
-            .context("Error found searching db prior to fetch")?.is_some()
-        {
-            return Ok(None)
+        loop {
+            while let Some(id) = queue.pop() {
+                if !seen.insert(id) || self.has_object(&id) {
+                    continue
+                }
+                match packs.get(&id) {
+                    // Objects of a pack already needed will be local once it is downloaded
+                    Some(pos) => to_download.extend(names[*pos].take()),
+                    None => {
+                        trace!("{} is not in any remote pack", id);
+                        not_packed.push(id);
+                    },
+                }
+            }
+            while in_flight.len() < self.parallelism {
+                match to_download.pop() {
+                    Some(name) => in_flight.push(download(name)),
+                    None => break,
+                }
+            }
+
+            let (name, bundle) = match in_flight.next().await {
+                Some(downloaded) => downloaded?,
+                None => break,
+            };
+            // Queue everything the pack's objects reference. Whatever is inside a needed pack
+            // will be skipped. Blobs reference nothing, so they are never decoded
+            for entry in bundle.index.iter() {
+                if entry_kind(&bundle, entry.pack_offset) == Some(Kind::Blob) {
+                    continue
+                }
+                let obj = bundle.find(entry.oid, &mut buf, &mut pack::cache::Never)
+                    .with_context(|| format!("Unable to read {} from pack {}", entry.oid, name))?
+                    .ok_or_else(|| Error::msg(format!("{} missing from pack {}", entry.oid, name)))?;
+                queue.extend(object_references(obj.kind, obj.data)?.into_iter().map(|(id, _)| id));
+            }
+            bundles.push(bundle);
         }
+        Ok((bundles, not_packed))
+    }
+    /// Fetch loose objects, and everything they reference. Objects already in the local database
+    /// are skipped along with everything below them. Walks with a queue rather than recursing,
+    /// so histories of any depth can be fetched
+    async fn fetch_loose(&self, ids: Vec<ObjectId>) -> Result<()> {
+        let mut seen = HashSet::new();
+        let mut queue = ids;
+        let mut in_flight = FuturesUnordered::new();
+        loop {
+            // Keep downloads going while the objects that arrived are searched for children
+            while in_flight.len() < self.parallelism {
+                let id = match queue.pop() {
+                    Some(id) => id,
+                    None => break,
+                };
+                // Already local, as is everything below it
+                if seen.insert(id) && !self.has_object(&id) {
+                    in_flight.push(self.fetch_object(id));
+                }
+            }
 
-        // If not, get data
-        let (data, code) = self.bucket.get_object_blocking(sha1.to_string())
-            .with_context(|| format!("Unable to fetch object\'{}\'", sha1))?;
-        debug!("Fetch for \'{}\': {}", sha1, code);
-        if code != 200 {
-            return Err(Error::msg(format!("Non-okay fetch for \'{}\': {}", sha1, code)))
+            let (id, kind, data) = match in_flight.next().await {
+                Some(fetched) => fetched?,
+                None => break,
+            };
+            trace!("{} was a {}. Searching for children", id, kind);
+            let children = object_references(kind, &data)
+                .with_context(|| format!("Unable to find children of \'{}\'", id))?;
+            queue.extend(children.into_iter().map(|(id, _)| id));
         }
+        Ok(())
+    }
+    /// Fetch a loose object from remote by id, save to local git object store. The kind is read
+    /// from the stored object, returned along with its data. The object is streamed through a
+    /// temporary file, and the data of blobs isn't returned as they reference nothing, so large
+    /// blobs are never held in memory. Loose objects are never encrypted, as they were only
+    /// written by older versions, and are checked against their id instead
+    async fn fetch_object(&self, id: ObjectId) -> Result<(ObjectId, Kind, Vec<u8>)> {
+        let sha1 = id.to_sha1_hex_string();
+        trace!("Fetching object {}", sha1);
+        let path = self.temp_path(&sha1)?;
+        let result = async {
+            self.download_file(&sha1, &path).await?;
+            self.save_object(&id, &path)
+        }.await;
+        fs::remove_file(&path).ok();
+        let (kind, data) = result?;
+        Ok((id, kind, data))
+    }
+    /// Save loose object `id` as stored in the bucket, downloaded to `path`, to the local git
+    /// object store
+    fn save_object(&self, id: &ObjectId, path: &Path) -> Result<(Kind, Vec<u8>)> {
+        let mut file = fs::File::open(path)
+            .with_context(|| format!("Unable to open {:?}", path))?;
+        let (kind, offset, size) = decode_loose_object(id, &mut file)?;
 
         // Save to git database
         {
             use git_odb::Write;
             use git_hash::Kind;
-            let _new_obj = self.git_db.write_buf(obj_type, &data, Kind::Sha1)
+            file.seek(SeekFrom::Start(offset))?;
+            let _new_obj = self.git_db.write_stream(kind, size, (&mut file).take(size), Kind::Sha1)
                 .context("Unable to write to git database")?;
         };
 
-        Ok(Some(data))
+        let mut data = Vec::new();
+        if kind != Kind::Blob {
+            file.seek(SeekFrom::Start(offset))?;
+            file.take(size).read_to_end(&mut data)?;
+        }
+        Ok((kind, data))
+    }
+    /// Move every loose object in the bucket into a single pack, where each object's kind is
+    /// recorded, then remove the loose copies
+    pub async fn migrate_loose_objects(&self) -> Result<()> {
+        // Loose objects are the top level keys named after their id
+        let ids: Vec<ObjectId> = self.list_keys("", Some("/")).await
+            .context("Unable to list loose objects")?
+            .iter()
+            .filter_map(|key| ObjectId::from_hex(key.as_bytes()).ok())
+            .collect();
+        if ids.is_empty() {
+            info!("No loose objects to migrate");
+            return Ok(())
+        }
+        self.progress(&format!("Migrating {} loose objects", ids.len()));
+
+        // The pack is built from the local database
+        let missing = ids.iter().filter(|id| !self.has_object(id)).cloned().collect();
+        parallel_map(missing, self.parallelism, |id| async move {
+            self.fetch_object(id).await
+                .with_context(|| format!("Unable to fetch object \'{}\'", id))
+        }).await?;
+        self.upload_pack(&ids).await.context("Unable to upload pack of loose objects")?;
+
+        parallel_map(ids, self.parallelism, |id| async move {
+            let key = id.to_sha1_hex_string();
+            let (_, code) = self.bucket().await.delete_object(self.key(&key)).await
+                .with_context(|| format!("Unable to delete loose object \'{}\'", key))?;
+            debug!("Delete for \'{}\': {}", key, code);
+            Ok(())
+        }).await?;
+        Ok(())
+    }
+}
+
+/// Kind of the pack entry at `offset`, read from its header and those of its delta bases without
+/// decoding any data. `None` if a base is outside the pack
+fn entry_kind(bundle: &pack::Bundle, mut offset: u64) -> Option<Kind> {
+    use pack::data::entry::Header;
+    loop {
+        let entry = bundle.pack.entry(offset);
+        offset = match entry.header {
+            Header::OfsDelta { base_distance } => entry.base_pack_offset(base_distance),
+            Header::RefDelta { base_id } => bundle.index.pack_offset_at_index(bundle.index.lookup(base_id)?),
+            header => return header.to_kind(),
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::git_s3::pack::write_pack;
+    use crate::git_s3::remote::TestRepo;
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::TcpListener;
+    use std::path::PathBuf;
+    use std::sync::{Arc, Mutex};
+
+    /// Serve the files under `dir` as the path style bucket `test`, recording every key fetched.
+    /// Listings hold every file, whatever their prefix
+    fn serve_bucket(dir: PathBuf) -> (String, Arc<Mutex<Vec<String>>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let endpoint = format!("http://{}", listener.local_addr().unwrap());
+        let fetched = Arc::new(Mutex::new(Vec::new()));
+        let log = fetched.clone();
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let mut request = String::new();
+                let mut reader = BufReader::new(&stream);
+                reader.read_line(&mut request).unwrap();
+                let mut line = String::new();
+                while reader.read_line(&mut line).unwrap() > 2 {
+                    line.clear();
+                }
+                let path = request.split(' ').nth(1).unwrap();
+                let body = match path.strip_prefix("/test/") {
+                    Some(query) if query.starts_with('?') => {
+                        let mut contents = String::new();
+                        for entry in fs::read_dir(dir.join("packs")).unwrap() {
+                            let entry = entry.unwrap();
+                            contents.push_str(&format!(
+                                "<Contents><Key>packs/{}</Key><LastModified>2021-01-01T00:00:00.000Z</LastModified>\
+                                 <ETag>\"0\"</ETag><Size>{}</Size><StorageClass>STANDARD</StorageClass></Contents>",
+                                entry.file_name().to_str().unwrap(), entry.metadata().unwrap().len()));
+                        }
+                        format!("<ListBucketResult><Name>test</Name><Prefix>packs/</Prefix><MaxKeys>1000</MaxKeys>\
+                                 <IsTruncated>false</IsTruncated>{}</ListBucketResult>", contents).into_bytes()
+                    },
+                    Some(key) => {
+                        log.lock().unwrap().push(key.to_string());
+                        fs::read(dir.join(key)).unwrap()
+                    },
+                    None => panic!("Request outside the bucket: {}", request),
+                };
+                write!(stream, "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n", body.len()).unwrap();
+                stream.write_all(&body).unwrap();
+            }
+        });
+        (endpoint, fetched)
+    }
+
+    #[test]
+    fn test_fetch_packs_downloads_only_needed_packs() {
+        let source = TestRepo::new("fetch-source");
+        let first = source.commit("a", "one");
+        let second = source.commit("b", "two");
+        let db = git_odb::compound::Db::at(source.dir.join(".git").join("objects")).unwrap();
+
+        // One pack per commit, the second holding only what the first doesn't
+        let bucket_dir = source.dir.join("bucket");
+        fs::create_dir_all(bucket_dir.join("packs")).unwrap();
+        let mut pack_names = Vec::new();
+        for revs in [vec![first.to_string()], vec![second.to_string(), format!("^{}", first)]].iter() {
+            let mut args = vec!["rev-list", "--objects"];
+            args.extend(revs.iter().map(String::as_str));
+            let objects: Vec<ObjectId> = source.git(&args).lines()
+                .map(|line| ObjectId::from_hex(&line.as_bytes()[..40]).unwrap())
+                .collect();
+            let path = bucket_dir.join("packs").join("building.pack");
+            let (hash, index) = write_pack(&db, &objects, flate2::Compression::fast(), &path).unwrap();
+            let name = format!("pack-{}", hash);
+            fs::rename(&path, bucket_dir.join("packs").join(format!("{}.pack", name))).unwrap();
+            fs::write(bucket_dir.join("packs").join(format!("{}.idx", name)), index).unwrap();
+            pack_names.push(name);
+        }
+
+        let (endpoint, fetched) = serve_bucket(bucket_dir);
+        let target = TestRepo::new("fetch-target");
+        let mut remote = target.remote_at(&endpoint);
+        let mut runtime = tokio::runtime::Builder::new().basic_scheduler().enable_all().build().unwrap();
+        let fetched_packs = || -> Vec<String> {
+            fetched.lock().unwrap().iter().filter(|key| key.ends_with(".pack")).cloned().collect()
+        };
+
+        let not_packed = runtime.block_on(remote.fetch_packs(vec![first])).unwrap();
+        assert!(not_packed.is_empty());
+        assert!(remote.has_object(&first));
+        assert!(!remote.has_object(&second));
+        assert_eq!(fetched_packs(), vec![format!("packs/{}.pack", pack_names[0])]);
+
+        let not_packed = runtime.block_on(remote.fetch_packs(vec![second])).unwrap();
+        assert!(not_packed.is_empty());
+        assert!(remote.has_object(&second));
+        assert_eq!(fetched_packs(), vec![
+            format!("packs/{}.pack", pack_names[0]),
+            format!("packs/{}.pack", pack_names[1]),
+        ]);
     }
 }
@@ -0,0 +1,98 @@
+use super::remote::Remote;
+
+use log::{info, debug, error};
+use anyhow::{Context, Error, Result};
+use std::fs;
+
+/// Key of the object naming the remote's default branch
+pub const HEAD_KEY: &str = "HEAD";
+
+/// Prefix of a symbolic ref, as stored in the HEAD object and local `HEAD` files
+const SYMREF_PREFIX: &str = "ref: ";
+
+impl Remote {
+    /// Read the ref the remote HEAD points to, if it has been set
+    pub async fn remote_head(&self) -> Result<Option<String>> {
+        let (data, code) = self.bucket().await.get_object(self.key(HEAD_KEY)).await
+            .context("Unable to fetch remote HEAD")?;
+        match code {
+            200 => (),
+            404 => return Ok(None),
+            _ => return Err(Error::msg(format!("Non-okay fetch for remote HEAD: {}", code))),
+        }
+        let data = self.unseal(HEAD_KEY, data)?;
+        let content = std::str::from_utf8(&data)
+            .context("Unable to convert remote HEAD to str")?;
+        let target = content.trim().strip_prefix(SYMREF_PREFIX)
+            .ok_or_else(|| Error::msg(format!("Remote HEAD is not a symref: {}", content.trim())))?;
+        Ok(Some(target.to_string()))
+    }
+
+    /// Point the remote HEAD at `branch`, either a full ref or a branch name. The branch must
+    /// already exist on the remote
+    pub async fn set_remote_head(&self, branch: &str) -> Result<()> {
+        let target = if branch.starts_with("refs/") {
+            branch.to_string()
+        } else {
+            format!("refs/heads/{}", branch)
+        };
+        if !self.remote_refs().await?.iter().any(|(name, _)| name == &target) {
+            return Err(Error::msg(format!("Remote has no ref {}", target)))
+        }
+        self.write_remote_head(&target, false).await
+    }
+
+    /// Set the remote HEAD after a push if it has never been set, picking the branch the local
+    /// HEAD is on if it was pushed, otherwise the first pushed branch
+    pub async fn init_remote_head(&self, pushed: &[(&str, &str)]) {
+        let branches: Vec<_> = pushed.iter()
+            .filter(|(_, dst)| dst.starts_with("refs/heads/"))
+            .collect();
+        if branches.is_empty() || self.options.dry_run {
+            return
+        }
+        let result = async {
+            if self.remote_head().await?.is_some() {
+                return Ok(())
+            }
+            let local_head = self.local_head();
+            let (_, dst) = branches.iter()
+                .find(|(src, _)| Some(*src) == local_head.as_deref())
+                .unwrap_or(&branches[0]);
+            self.write_remote_head(dst, true).await
+        }.await;
+        // The refs themselves were pushed, so this isn't worth failing the push over
+        if let Err(e) = result {
+            error!("Unable to set remote HEAD: {:?}", e);
+        }
+    }
+
+    /// Branch the local HEAD is on, if any
+    fn local_head(&self) -> Option<String> {
+        let content = fs::read_to_string(self.git_dir.join("HEAD")).ok()?;
+        content.trim().strip_prefix(SYMREF_PREFIX).map(String::from)
+    }
+
+    /// Write the remote HEAD. With `create_only`, a HEAD set by someone else in the meantime is
+    /// left alone
+    async fn write_remote_head(&self, target: &str, create_only: bool) -> Result<()> {
+        info!("Setting remote HEAD to {}", target);
+        let content = format!("{}{}\n", SYMREF_PREFIX, target);
+        let mut bucket = self.bucket().await;
+        if create_only {
+            bucket.add_header("If-None-Match", "*");
+        }
+        let data = self.seal(HEAD_KEY, content.as_bytes())?;
+        let (_, code) = bucket.put_object(self.key(HEAD_KEY), &data).await
+            .context("Unable to update remote HEAD")?;
+        debug!("Put for remote HEAD: {}", code);
+        match code {
+            200 => Ok(()),
+            409 | 412 if create_only => {
+                info!("Remote HEAD was set concurrently, keeping it");
+                Ok(())
+            },
+            _ => Err(Error::msg(format!("Non-okay push for remote HEAD: {}", code))),
+        }
+    }
+}
@@ -0,0 +1,115 @@
+use super::remote::Remote;
+
+use log::trace;
+use anyhow::{Context, Error, Result};
+use std::path::{Path, PathBuf};
+
+use git_hash::ObjectId;
+use git_ref::file::Store;
+use git_ref::mutable::Target;
+
+/// Symbolic refs followed before giving up on a ref, in case of loops
+const MAX_SYMREF_DEPTH: usize = 5;
+
+impl Remote {
+    /// Resolve a local ref to the object it points to, following symbolic refs but not peeling
+    /// tags. Loose and packed refs are both searched, and a full hex sha resolves to itself
+    pub fn resolve_local_ref(&self, name: &str) -> Result<ObjectId> {
+        if name.len() == 40 {
+            if let Ok(id) = ObjectId::from_hex(name.as_bytes()) {
+                return Ok(id)
+            }
+        }
+
+        // Worktrees have their own HEAD, every other ref lives in the common dir with the
+        // packed refs
+        let worktree_store = Store::from(self.git_dir.clone());
+        let common_store = Store::from(self.common_dir.clone());
+        let packed = common_store.packed()
+            .context("Unable to read packed refs")?;
+
+        let mut name = name.to_string();
+        for _ in 0..MAX_SYMREF_DEPTH {
+            let store = if name.starts_with("refs/") { &common_store } else { &worktree_store };
+            let reference = store.find(name.as_str(), packed.as_ref())
+                .with_context(|| format!("Unable to look up local ref {}", name))?
+                .ok_or_else(|| Error::msg(format!("Local ref {} does not exist", name)))?;
+            match reference.target() {
+                Target::Peeled(id) => {
+                    trace!("Local ref {} is {}", name, id);
+                    // Newer gitoxide crates have their own id type
+                    return Ok(ObjectId::from_20_bytes(id.as_bytes()))
+                },
+                Target::Symbolic(target) => {
+                    trace!("Local ref {} points to {}", name, target.as_bstr());
+                    name = target.as_bstr().to_string();
+                },
+            }
+        }
+        Err(Error::msg(format!("Local ref {} is nested too deeply", name)))
+    }
+}
+
+/// Directory holding the objects and refs shared by every worktree of the repository at
+/// `git_dir`. Only differs from `git_dir` for linked worktrees
+pub fn common_dir(git_dir: &Path) -> Result<PathBuf> {
+    let path = git_dir.join("commondir");
+    if !path.exists() {
+        return Ok(git_dir.to_path_buf())
+    }
+    let content = std::fs::read_to_string(&path)
+        .with_context(|| format!("Unable to read {:?}", path))?;
+    // Relative to the worktree's git dir
+    Ok(git_dir.join(content.trim()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::git_s3::remote::TestRepo;
+
+    #[test]
+    fn test_resolve_local_ref() {
+        let repo = TestRepo::new("resolve-local-ref");
+        let first = repo.commit("a", "a");
+        repo.git(&["tag", "-a", "-m", "v1", "v1"]);
+        let second = repo.commit("b", "b");
+        repo.git(&["pack-refs", "--all"]);
+        let remote = repo.remote();
+
+        // Packed refs, and HEAD pointing at one
+        assert!(!repo.dir.join(".git/refs/heads/main").exists());
+        assert_eq!(remote.resolve_local_ref("refs/heads/main").unwrap(), second);
+        assert_eq!(remote.resolve_local_ref("HEAD").unwrap(), second);
+        // Tags aren't peeled
+        let tag = remote.resolve_local_ref("refs/tags/v1").unwrap();
+        assert_eq!(tag, repo.id("v1"));
+        assert_ne!(tag, first);
+        // Loose refs win over packed ones
+        repo.git(&["update-ref", "refs/heads/main", &first.to_sha1_hex_string()]);
+        assert_eq!(remote.resolve_local_ref("HEAD").unwrap(), first);
+        // A full sha is its own object, whether the object exists or not
+        let sha = "0123456789012345678901234567890123456789";
+        assert_eq!(remote.resolve_local_ref(sha).unwrap(), ObjectId::from_hex(sha.as_bytes()).unwrap());
+
+        let e = remote.resolve_local_ref("refs/heads/missing").unwrap_err();
+        assert_eq!(format!("{}", e), "Local ref refs/heads/missing does not exist");
+    }
+    #[test]
+    fn test_resolve_local_ref_in_worktree() {
+        let repo = TestRepo::new("resolve-worktree-ref");
+        let first = repo.commit("a", "a");
+        repo.git(&["worktree", "add", "--quiet", "-b", "feature", "wt"]);
+        repo.git(&["-C", "wt", "commit", "--quiet", "--allow-empty", "-m", "feature"]);
+        let feature = repo.id("feature");
+
+        let mut remote = repo.remote();
+        remote.git_dir = repo.dir.join(".git/worktrees/wt");
+        remote.common_dir = common_dir(&remote.git_dir).unwrap();
+        assert_eq!(remote.common_dir.canonicalize().unwrap(), repo.dir.join(".git").canonicalize().unwrap());
+        // HEAD is the worktree's own, other refs are shared
+        assert_eq!(remote.resolve_local_ref("HEAD").unwrap(), feature);
+        assert_eq!(remote.resolve_local_ref("refs/heads/main").unwrap(), first);
+        assert_eq!(remote.resolve_local_ref("refs/heads/feature").unwrap(), feature);
+    }
+}
@@ -1,5 +1,15 @@
+mod codec;
+mod config;
+mod creds;
+mod crypt;
 mod fetch;
+mod head;
+mod local;
+mod multipart;
+mod options;
+mod pack;
 mod push;
+mod refs;
 mod util;
 pub mod cmd;
 pub mod remote;
@@ -0,0 +1,134 @@
+use super::remote::Remote;
+use super::util::parallel_map;
+
+use log::{info, debug, error};
+use anyhow::{Context, Error, Result};
+use std::fs;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::Path;
+
+use s3::command::Command;
+use s3::request::Request;
+use s3::serde_types::{CompleteMultipartUploadData, InitiateMultipartUploadResponse, Part};
+use s3::signing::uri_encode;
+
+/// Size of each part of a multipart upload. Files up to this size are uploaded in a single
+/// request. S3 allows at most 10,000 parts, and no part but the last under 5 MiB
+pub const PART_SIZE: u64 = 16 * 1024 * 1024;
+
+impl Remote {
+    /// Upload the file at `path` as object `name`. Anything larger than a single part is streamed
+    /// from disk through a multipart upload, `s3.parallelism` parts at a time, so objects of any
+    /// size can be uploaded without loading them into memory. Only packs are uploaded this way,
+    /// so the object gets their storage class
+    pub async fn upload_file(&self, name: &str, path: &Path) -> Result<()> {
+        let key = &self.key(name);
+        let size = fs::metadata(path)
+            .with_context(|| format!("Unable to read {:?}", path))?
+            .len();
+        info!("Uploading {} ({} bytes)", key, size);
+        if size <= PART_SIZE {
+            let data = fs::read(path)
+                .with_context(|| format!("Unable to read {:?}", path))?;
+            let (_, code) = self.pack_bucket().await.put_object(key, &data).await
+                .with_context(|| format!("Unable to upload \'{}\'", key))?;
+            if code != 200 {
+                return Err(Error::msg(format!("Non-okay push for \'{}\': {}", key, code)))
+            }
+            return Ok(())
+        }
+
+        let upload_id = self.initiate_multipart(key).await?;
+        let result = async {
+            let parts = self.upload_parts(key, &upload_id, path, size).await?;
+            self.complete_multipart(key, &upload_id, parts).await
+        }.await;
+        // Parts of an unfinished upload are stored, and billed, until it is aborted
+        if result.is_err() {
+            if let Err(e) = self.abort_multipart(key, &upload_id).await {
+                error!("Unable to abort upload of \'{}\': {:?}", key, e);
+            }
+        }
+        result.with_context(|| format!("Unable to upload \'{}\'", key))
+    }
+
+    /// Start a multipart upload to `key`, returning its upload id
+    async fn initiate_multipart(&self, key: &str) -> Result<String> {
+        let path = format!("{}?uploads", key);
+        // The storage class is set when starting the upload, not on each part
+        let bucket = self.pack_bucket().await;
+        let (data, code) = Request::new(&bucket, &path, Command::InitiateMultipartUpload)
+            .response_data_future(false).await
+            .with_context(|| format!("Unable to start upload of \'{}\'", key))?;
+        if code != 200 {
+            return Err(Error::msg(format!("Non-okay start of upload for \'{}\': {}", key, code)))
+        }
+        let response: InitiateMultipartUploadResponse = serde_xml_rs::from_reader(data.as_slice())
+            .with_context(|| format!("Invalid response starting upload of \'{}\'", key))?;
+        debug!("Started upload {} of \'{}\'", response.upload_id, key);
+        Ok(response.upload_id)
+    }
+
+    /// Upload the file at `path` as the parts of upload `upload_id`, returning each part's ETag
+    async fn upload_parts(&self, key: &str, upload_id: &str, path: &Path, size: u64) -> Result<Vec<Part>> {
+        let count = size.div_ceil(PART_SIZE);
+        self.progress(&format!("Uploading {} in {} parts", key, count));
+        let part_numbers: Vec<u32> = (1..=count as u32).collect();
+        parallel_map(part_numbers, self.parallelism, |part_number| async move {
+            // Only the parts in flight are ever in memory
+            let offset = (part_number as u64 - 1) * PART_SIZE;
+            let mut content = Vec::new();
+            let mut file = fs::File::open(path)
+                .with_context(|| format!("Unable to open {:?}", path))?;
+            file.seek(SeekFrom::Start(offset))?;
+            file.take(PART_SIZE).read_to_end(&mut content)
+                .with_context(|| format!("Unable to read {:?}", path))?;
+
+            // Same as a plain put, with the part in the query
+            let part_path = format!("{}?partNumber={}&uploadId={}",
+                key, part_number, uri_encode(upload_id, true));
+            let command = Command::PutObject { content: &content, content_type: "application/octet-stream" };
+            let (etag, code) = Request::new(&self.bucket().await, &part_path, command)
+                .response_data_future(true).await
+                .with_context(|| format!("Unable to upload part {} of \'{}\'", part_number, key))?;
+            if code != 200 {
+                return Err(Error::msg(format!("Non-okay upload of part {} of \'{}\': {}", part_number, key, code)))
+            }
+            let etag = String::from_utf8(etag)
+                .with_context(|| format!("Invalid ETag for part {} of \'{}\'", part_number, key))?;
+            debug!("Uploaded part {} of \'{}\': {}", part_number, key, etag);
+            Ok(Part { part_number, etag })
+        }).await
+    }
+
+    /// Finish upload `upload_id`, joining `parts` into the object at `key`
+    async fn complete_multipart(&self, key: &str, upload_id: &str, parts: Vec<Part>) -> Result<()> {
+        let path = format!("{}?uploadId={}", key, uri_encode(upload_id, true));
+        let command = Command::CompleteMultipartUpload {
+            upload_id,
+            data: CompleteMultipartUploadData { parts },
+        };
+        let (data, code) = Request::new(&self.bucket().await, &path, command)
+            .response_data_future(false).await
+            .with_context(|| format!("Unable to complete upload of \'{}\'", key))?;
+        // Completing can fail after the 200 has been sent, the error is then in the body
+        let body = String::from_utf8_lossy(&data);
+        if code != 200 || body.contains("<Error>") {
+            return Err(Error::msg(format!("Non-okay completion of upload for \'{}\': {} {}", key, code, body)))
+        }
+        Ok(())
+    }
+
+    /// Abandon upload `upload_id`, removing the parts uploaded so far
+    async fn abort_multipart(&self, key: &str, upload_id: &str) -> Result<()> {
+        let path = format!("{}?uploadId={}", key, uri_encode(upload_id, true));
+        let (_, code) = Request::new(&self.bucket().await, &path, Command::AbortMultipartUpload { upload_id })
+            .response_data_future(false).await
+            .with_context(|| format!("Unable to abort upload of \'{}\'", key))?;
+        debug!("Abort of upload for \'{}\': {}", key, code);
+        match code {
+            200 | 204 => Ok(()),
+            _ => Err(Error::msg(format!("Non-okay abort of upload for \'{}\': {}", key, code))),
+        }
+    }
+}
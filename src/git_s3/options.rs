@@ -0,0 +1,86 @@
+use anyhow::{Context, Error, Result};
+
+/// Transport options git sets through the `option` command. Fetch and push read these to decide
+/// how to behave
+#[derive(Debug)]
+pub struct Options {
+    /// How much to report on stderr. 0 is quiet, 1 is the default, higher is more verbose
+    pub verbosity: usize,
+    /// Report progress on stderr
+    pub progress: bool,
+    /// Go through the motions of a push without changing anything on the remote
+    pub dry_run: bool,
+    /// After a fetch, verify everything reachable from the fetched objects exists locally
+    pub check_connectivity: bool,
+    /// Allow non fast-forward updates for every pushed ref
+    pub force: bool,
+    /// Update either every ref of a push or none of them
+    pub atomic: bool,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Options {
+            verbosity: 1,
+            progress: false,
+            dry_run: false,
+            check_connectivity: false,
+            force: false,
+            atomic: false,
+        }
+    }
+}
+
+impl Options {
+    /// Set option `name` from the value git passed. Returns `Ok(false)` if the option isn't
+    /// supported, or an error if the value isn't valid for it
+    pub fn set(&mut self, name: &str, value: &str) -> Result<bool> {
+        match name {
+            "verbosity" => self.verbosity = value.parse()
+                .with_context(|| format!("invalid verbosity \'{}\'", value))?,
+            "progress" => self.progress = parse_bool(value)?,
+            "dry-run" => self.dry_run = parse_bool(value)?,
+            "check-connectivity" => self.check_connectivity = parse_bool(value)?,
+            "force" => self.force = parse_bool(value)?,
+            "atomic" => self.atomic = parse_bool(value)?,
+            // Nothing changes based on these. Git asks for tags it wants in a second fetch
+            "cloning" | "followtags" => { parse_bool(value)?; },
+            // Includes depth: packs are fetched whole, so shallow fetches aren't possible
+            _ => return Ok(false),
+        }
+        Ok(true)
+    }
+}
+
+/// Parse a boolean option value as sent by git
+fn parse_bool(value: &str) -> Result<bool> {
+    match value {
+        "true" => Ok(true),
+        "false" => Ok(false),
+        _ => Err(Error::msg(format!("invalid boolean \'{}\'", value))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_set_options() {
+        let mut options = Options::default();
+        assert!(options.set("dry-run", "true").unwrap());
+        assert!(options.set("verbosity", "2").unwrap());
+        assert!(options.set("cloning", "false").unwrap());
+        assert!(options.dry_run);
+        assert_eq!(options.verbosity, 2);
+    }
+    #[test]
+    fn test_unsupported_option() {
+        assert!(!Options::default().set("depth", "1").unwrap());
+    }
+    #[test]
+    fn test_invalid_option_value() {
+        assert!(Options::default().set("force", "yes").is_err());
+        assert!(Options::default().set("verbosity", "-1").is_err());
+    }
+}
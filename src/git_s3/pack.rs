@@ -0,0 +1,495 @@
+use super::codec::{self, Codec};
+use super::remote::Remote;
+
+use log::{info, trace, debug};
+use anyhow::{Context, Error, Result};
+use std::fs;
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+
+use git_hash::ObjectId;
+use git_object::Kind;
+use git_odb::pack;
+
+/// Key prefix packfiles and their indexes are stored under in the bucket
+pub const PACK_PREFIX: &str = "packs/";
+
+impl Remote {
+    /// Directory remote pack indexes are cached in. Packs are named after their checksum, so a
+    /// cached index never goes stale
+    fn pack_cache_dir(&self) -> PathBuf {
+        self.common_dir.join("s3").join("packs")
+    }
+
+    /// Name a pack is stored under in the bucket. Packs are named `pack-<sha>` after their
+    /// checksum, with encryption that name is hidden behind one derived from it
+    fn remote_pack_name(&self, name: &str) -> String {
+        match &self.cipher {
+            Some(cipher) => cipher.name(name),
+            None => name.to_string(),
+        }
+    }
+
+    /// List the names all packs are stored under in the bucket. A pack is only listed once its
+    /// index exists, as the index is uploaded last
+    pub async fn list_remote_packs(&self) -> Result<Vec<String>> {
+        let keys = self.list_keys(PACK_PREFIX, None).await
+            .context("Unable to list remote packs")?;
+        Ok(keys.iter()
+            .filter_map(|k| k.strip_prefix(PACK_PREFIX)?.strip_suffix(".idx"))
+            .map(String::from)
+            .collect())
+    }
+
+    /// Path to the cached index of a remote pack, downloading it first if it isn't there yet
+    async fn cached_pack_index(&self, name: &str) -> Result<PathBuf> {
+        let cache_dir = self.pack_cache_dir();
+        let path = cache_dir.join(format!("{}.idx", name));
+        if !path.exists() {
+            debug!("Downloading index for pack {}", name);
+            // Write to a temporary name first so a partial download is never used
+            fs::create_dir_all(&cache_dir)
+                .with_context(|| format!("Unable to create pack cache {:?}", cache_dir))?;
+            let tmp_path = path.with_extension("idx.tmp");
+            self.download_pack_object(name, "idx", &tmp_path).await?;
+            fs::rename(&tmp_path, &path)
+                .with_context(|| format!("Unable to move pack index to {:?}", path))?;
+        }
+        Ok(path)
+    }
+
+    /// Load the index of a remote pack
+    pub async fn remote_pack_index(&self, name: &str) -> Result<pack::index::File> {
+        let path = self.cached_pack_index(name).await?;
+        pack::index::File::at(&path)
+            .with_context(|| format!("Unable to load pack index {:?}", path))
+    }
+
+    /// Download a remote pack into the local object database alongside its index, returning it
+    /// ready to be read from
+    pub async fn download_pack(&self, name: &str) -> Result<pack::Bundle> {
+        info!("Downloading pack {}", name);
+        self.progress(&format!("Downloading {}", name));
+        // The local name comes from the index, as the remote one may be derived from it
+        let checksum = self.remote_pack_index(name).await?.pack_checksum();
+        let local_name = format!("pack-{}", checksum);
+        if self.remote_pack_name(&local_name) != name {
+            return Err(Error::msg(format!("Index of pack {} is for another pack", name)))
+        }
+        let pack_dir = self.common_dir.join("objects").join("pack");
+        fs::create_dir_all(&pack_dir)
+            .with_context(|| format!("Unable to create pack directory {:?}", pack_dir))?;
+        let tmp_path = pack_dir.join(format!("{}.pack.tmp", local_name));
+        let stored_path = self.temp_path(&format!("{}.pack", name))?;
+        self.download_pack_object(name, "pack", &stored_path).await?;
+        let codec = codec::decode_file(&stored_path, &tmp_path)?;
+        debug!("Pack {} was stored with codec {:?}", name, codec);
+
+        // A pack's trailer is the checksum it is named after
+        let trailer = pack_trailer(&tmp_path)
+            .with_context(|| format!("Unable to read pack {:?}", tmp_path))?;
+        if trailer != Some(checksum) {
+            fs::remove_file(&tmp_path).ok();
+            return Err(Error::msg(format!("Checksum mismatch for downloaded pack {}", name)))
+        }
+        let remote_index = self.cached_pack_index(name).await?;
+        let (local_name, tmp_path, index_data) = if codec == Codec::None {
+            (local_name, tmp_path, None)
+        } else {
+            // Entries aren't deflated when a codec compresses the whole pack. Deflate them for
+            // the local copy, which is named after its own checksum then
+            let deflated_path = pack_dir.join(format!("{}.pack.deflated", local_name));
+            let result = rewrite_pack(&tmp_path, &remote_index, flate2::Compression::fast(), &deflated_path);
+            fs::remove_file(&tmp_path).ok();
+            let (hash, index_data) = match result {
+                Ok(rewritten) => rewritten,
+                Err(e) => {
+                    fs::remove_file(&deflated_path).ok();
+                    return Err(e.context(format!("Unable to deflate pack {}", name)))
+                },
+            };
+            (format!("pack-{}", hash), deflated_path, Some(index_data))
+        };
+        let pack_path = pack_dir.join(format!("{}.pack", local_name));
+        fs::rename(&tmp_path, &pack_path)
+            .with_context(|| format!("Unable to move pack to {:?}", pack_path))?;
+        // Index goes in last, git only picks up packs with an index
+        let index_path = pack_path.with_extension("idx");
+        match index_data {
+            Some(data) => fs::write(&index_path, data).map(|_| ()),
+            None => fs::copy(&remote_index, &index_path).map(|_| ()),
+        }.with_context(|| format!("Unable to write pack index to {:?}", index_path))?;
+
+        pack::Bundle::at(&index_path)
+            .with_context(|| format!("Unable to load pack {:?}", index_path))
+    }
+
+    /// Stream the pack or index of a remote pack into the file at `path`, decrypted if needed
+    async fn download_pack_object(&self, name: &str, ext: &str, path: &Path) -> Result<()> {
+        let key = format!("{}{}.{}", PACK_PREFIX, name, ext);
+        let download_path = self.temp_path(&format!("{}.{}.download", name, ext))?;
+        if let Err(e) = self.download_file(&key, &download_path).await {
+            fs::remove_file(&download_path).ok();
+            return Err(e)
+        }
+        self.unseal_file(&key, &download_path, path)
+    }
+
+    /// Write the passed objects into a single packfile, index it, and upload both under
+    /// `packs/`. The pack is uploaded before its index so readers never see a partial pack
+    pub async fn upload_pack(&self, objects: &[ObjectId]) -> Result<()> {
+        info!("Building pack of {} objects", objects.len());
+        let pack_path = self.temp_path("upload.pack")?;
+        let result = self.write_and_upload_pack(objects, &pack_path).await;
+        fs::remove_file(&pack_path).ok();
+        result
+    }
+
+    /// Build the pack in the file at `pack_path` rather than in memory, then upload it
+    async fn write_and_upload_pack(&self, objects: &[ObjectId], pack_path: &Path) -> Result<()> {
+        // Entries only store their data when the whole pack is compressed by a codec
+        let level = match self.compression {
+            Codec::None => flate2::Compression::fast(),
+            _ => flate2::Compression::none(),
+        };
+        let (pack_hash, index_data) = write_pack(&self.git_db, objects, level, pack_path)?;
+        trace!("Pack is {} bytes", fs::metadata(pack_path)?.len());
+        let name = format!("pack-{}", pack_hash.to_sha1_hex_string());
+        self.progress(&format!("Uploading {} with {} objects", name, objects.len()));
+
+        // Upload pack, then index
+        let remote_name = self.remote_pack_name(&name);
+        let key = format!("{}{}.pack", PACK_PREFIX, remote_name);
+        self.upload_pack_file(&key, pack_path).await?;
+        let key = format!("{}{}.idx", PACK_PREFIX, remote_name);
+        let stored_index = self.seal(&key, &index_data)?;
+        info!("Uploading {} ({} bytes)", key, stored_index.len());
+        let (_, code) = self.pack_bucket().await.put_object(self.key(&key), &stored_index).await
+            .with_context(|| format!("Unable to upload \'{}\'", key))?;
+        if code != 200 {
+            return Err(Error::msg(format!("Non-okay push for \'{}\': {}", key, code)))
+        }
+
+        // The remote has it now, save the index to skip downloading it later
+        let cache_dir = self.pack_cache_dir();
+        fs::create_dir_all(&cache_dir)
+            .with_context(|| format!("Unable to create pack cache {:?}", cache_dir))?;
+        fs::write(cache_dir.join(format!("{}.idx", remote_name)), &index_data)
+            .context("Unable to cache pack index")?;
+        Ok(())
+    }
+
+    /// Upload the pack at `pack_path` to `key`, compressed then encrypted first as configured.
+    /// Each step writes a new temporary file
+    async fn upload_pack_file(&self, key: &str, pack_path: &Path) -> Result<()> {
+        let compressed_path = self.temp_path("upload.pack.compressed")?;
+        let encrypted_path = self.temp_path("upload.pack.encrypted")?;
+        let result = async {
+            let mut path = pack_path;
+            if self.compression != Codec::None {
+                codec::encode_file(self.compression, path, &compressed_path)?;
+                debug!("Compressed pack with {:?} to {} bytes", self.compression,
+                    fs::metadata(&compressed_path)?.len());
+                path = &compressed_path;
+            }
+            if self.cipher.is_some() {
+                self.seal_file(key, path, &encrypted_path)?;
+                path = &encrypted_path;
+            }
+            self.upload_file(key, path).await
+        }.await;
+        fs::remove_file(&compressed_path).ok();
+        fs::remove_file(&encrypted_path).ok();
+        result
+    }
+}
+
+/// Size of the buffer objects are copied through into a pack. No more than this much of an
+/// object's data is held in memory at once, unless it is stored as a delta
+const COPY_BUFFER_SIZE: usize = 64 * 1024;
+
+/// Writer that hashes everything passed through it, and counts the bytes written
+struct HashWriter<W> {
+    inner: W,
+    hash: git_features::hash::Sha1,
+    crc32: u32,
+    written: u64,
+}
+
+impl<W: Write> HashWriter<W> {
+    fn new(inner: W) -> Self {
+        HashWriter { inner, hash: Default::default(), crc32: 0, written: 0 }
+    }
+}
+
+impl<W: Write> Write for HashWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let len = self.inner.write(buf)?;
+        self.hash.update(&buf[..len]);
+        self.crc32 = git_features::hash::crc32_update(self.crc32, &buf[..len]);
+        self.written += len as u64;
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Kind and size of an object, along with a reader of its data
+type ObjectReader<'a> = (Kind, u64, Box<dyn Read + 'a>);
+
+/// Write the passed objects from `db` as full (non-delta) entries of a new pack at `path`, each
+/// deflated at `level`. Objects are streamed from where they are stored, so large ones are never
+/// held in memory. Returns the pack's checksum, along with the data of its index
+pub fn write_pack(db: &git_odb::compound::Db, objects: &[ObjectId], level: flate2::Compression, path: &Path) -> Result<(ObjectId, Vec<u8>)> {
+    write_pack_from(objects, |id| object_reader(db, id), level, path)
+}
+
+/// Write the pack at `path` again at `out`, with every entry deflated at `level`. Its index is
+/// at `index_path`. Returns the new pack's checksum, along with the data of its index
+fn rewrite_pack(path: &Path, index_path: &Path, level: flate2::Compression, out: &Path) -> Result<(ObjectId, Vec<u8>)> {
+    let bundle = pack::Bundle {
+        pack: pack::data::File::at(path)
+            .with_context(|| format!("Unable to load pack {:?}", path))?,
+        index: pack::index::File::at(index_path)
+            .with_context(|| format!("Unable to load pack index {:?}", index_path))?,
+    };
+    // In pack order, so the pack is read from start to end
+    let mut entries: Vec<pack::index::Entry> = bundle.index.iter().collect();
+    entries.sort_by_key(|entry| entry.pack_offset);
+    let ids: Vec<ObjectId> = entries.into_iter().map(|entry| entry.oid).collect();
+    write_pack_from(&ids, |id| {
+        pack_entry_reader(&bundle, id)?
+            .ok_or_else(|| Error::msg(format!("{} missing from its pack", id)))
+    }, level, out)
+}
+
+/// Write the passed objects, each opened with `open`, as the entries of a new pack at `path`
+fn write_pack_from<'a>(
+    objects: &[ObjectId], open: impl Fn(&ObjectId) -> Result<ObjectReader<'a>>, level: flate2::Compression, path: &Path,
+) -> Result<(ObjectId, Vec<u8>)> {
+    let file = fs::File::create(path)
+        .with_context(|| format!("Unable to create pack {:?}", path))?;
+    let mut out = HashWriter::new(io::BufWriter::new(file));
+    out.write_all(b"PACK")?;
+    out.write_all(&2u32.to_be_bytes())?;
+    out.write_all(&(objects.len() as u32).to_be_bytes())?;
+
+    let mut buf = vec![0; COPY_BUFFER_SIZE];
+    let mut entries = Vec::with_capacity(objects.len());
+    for id in objects {
+        let (kind, size, mut data) = open(id)
+            .with_context(|| format!("Unable to read object {}", id))?;
+        let offset = out.written;
+        out.crc32 = 0;
+        let header = match kind {
+            Kind::Commit => pack::data::entry::Header::Commit,
+            Kind::Tree => pack::data::entry::Header::Tree,
+            Kind::Blob => pack::data::entry::Header::Blob,
+            Kind::Tag => pack::data::entry::Header::Tag,
+        };
+        header.to_write(size, &mut out)?;
+        let mut encoder = flate2::write::ZlibEncoder::new(&mut out, level);
+        let mut copied = 0;
+        loop {
+            let len = data.read(&mut buf)?;
+            if len == 0 {
+                break
+            }
+            encoder.write_all(&buf[..len])?;
+            copied += len as u64;
+        }
+        encoder.finish()?;
+        if copied != size {
+            return Err(Error::msg(format!("Object {} is {} bytes rather than {}", id, copied, size)))
+        }
+        entries.push((*id, out.crc32, offset));
+    }
+
+    let HashWriter { inner, hash, .. } = out;
+    let pack_hash = ObjectId::from(hash.digest());
+    let mut file = inner.into_inner().map_err(|e| e.into_error())?;
+    file.write_all(pack_hash.as_slice())
+        .with_context(|| format!("Unable to write pack {:?}", path))?;
+    Ok((pack_hash, pack_index(entries, &pack_hash)))
+}
+
+/// Open object `id` in `db` for reading. Loose objects and full pack entries are inflated as
+/// they are read. Objects stored as deltas have to be resolved whole, git only stores objects up
+/// to `core.bigFileThreshold` that way
+fn object_reader<'a>(db: &'a git_odb::compound::Db, id: &ObjectId) -> Result<ObjectReader<'a>> {
+    for bundle in db.packs.iter() {
+        if let Some(reader) = pack_entry_reader(bundle, id)? {
+            return Ok(reader)
+        }
+    }
+
+    let hex = id.to_sha1_hex_string();
+    let path = db.loose.path.join(&hex[..2]).join(&hex[2..]);
+    let file = fs::File::open(&path)
+        .with_context(|| format!("Object {} not found in database", id))?;
+    let mut reader = flate2::read::ZlibDecoder::new(io::BufReader::new(file));
+    // Loose objects start with a `<kind> <size>\0` header
+    let mut header = Vec::new();
+    loop {
+        let mut byte = [0];
+        reader.read_exact(&mut byte)?;
+        match byte[0] {
+            0 => break,
+            _ if header.len() >= 32 => return Err(Error::msg(format!("Object {} has no header", id))),
+            b => header.push(b),
+        }
+    }
+    let header = std::str::from_utf8(&header)
+        .with_context(|| format!("Invalid header for object {}", id))?;
+    let (kind, size) = header.split_once(' ')
+        .and_then(|(kind, size)| Some((Kind::from_bytes(kind.as_bytes()).ok()?, size.parse().ok()?)))
+        .ok_or_else(|| Error::msg(format!("Invalid header \'{}\' for object {}", header, id)))?;
+    Ok((kind, size, Box::new(reader.take(size))))
+}
+
+/// Open object `id` in `bundle` for reading, `None` if the pack doesn't have it
+fn pack_entry_reader<'a>(bundle: &'a pack::Bundle, id: &ObjectId) -> Result<Option<ObjectReader<'a>>> {
+    let index = match bundle.index.lookup(id) {
+        Some(index) => index,
+        None => return Ok(None),
+    };
+    let entry = bundle.pack.entry(bundle.index.pack_offset_at_index(index));
+    if let Some(kind) = entry.header.to_kind() {
+        let data = bundle.pack.entry_slice(entry.data_offset..bundle.pack.pack_end() as u64)
+            .ok_or_else(|| Error::msg(format!("Entry for {} is outside its pack", id)))?;
+        let reader = flate2::read::ZlibDecoder::new(data).take(entry.decompressed_size);
+        return Ok(Some((kind, entry.decompressed_size, Box::new(reader))))
+    }
+    let mut buf = Vec::new();
+    let obj = bundle.find(id, &mut buf, &mut pack::cache::Never)?
+        .ok_or_else(|| Error::msg(format!("{} missing from its pack", id)))?;
+    let kind = obj.kind;
+    let size = obj.data.len() as u64;
+    Ok(Some((kind, size, Box::new(io::Cursor::new(buf)))))
+}
+
+/// Version 2 index of a pack holding `entries`, each an object id with the CRC32 of its entry and
+/// its offset in the pack
+fn pack_index(mut entries: Vec<(ObjectId, u32, u64)>, pack_hash: &ObjectId) -> Vec<u8> {
+    entries.sort_by_key(|(id, _, _)| *id);
+    let mut data = b"\xfftOc".to_vec();
+    data.extend_from_slice(&2u32.to_be_bytes());
+    for byte in 0..=255u8 {
+        let count = entries.iter().take_while(|(id, _, _)| id.as_slice()[0] <= byte).count();
+        data.extend_from_slice(&(count as u32).to_be_bytes());
+    }
+    for (id, _, _) in entries.iter() {
+        data.extend_from_slice(id.as_slice());
+    }
+    for (_, crc32, _) in entries.iter() {
+        data.extend_from_slice(&crc32.to_be_bytes());
+    }
+    // Offsets past 31 bits go in a table of 64 bit offsets, pointed to with the top bit set
+    let mut large_offsets = Vec::new();
+    for (_, _, offset) in entries.iter() {
+        let offset = if *offset < 0x8000_0000 {
+            *offset as u32
+        } else {
+            large_offsets.extend_from_slice(&offset.to_be_bytes());
+            0x8000_0000 | (large_offsets.len() / 8 - 1) as u32
+        };
+        data.extend_from_slice(&offset.to_be_bytes());
+    }
+    data.extend_from_slice(&large_offsets);
+    data.extend_from_slice(pack_hash.as_slice());
+    let mut hash = git_features::hash::Sha1::default();
+    hash.update(&data);
+    data.extend_from_slice(&hash.digest());
+    data
+}
+
+/// Read the checksum trailing the pack at `path`, `None` if it is too short to have one
+fn pack_trailer(path: &Path) -> io::Result<Option<ObjectId>> {
+    use io::{Read, Seek, SeekFrom};
+    let mut file = fs::File::open(path)?;
+    if file.metadata()?.len() < 20 {
+        return Ok(None)
+    }
+    let mut trailer = [0; 20];
+    file.seek(SeekFrom::End(-20))?;
+    file.read_exact(&mut trailer)?;
+    Ok(Some(ObjectId::from_20_bytes(&trailer)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use git_features::progress;
+
+    #[test]
+    fn test_write_pack_streams_large_objects() {
+        use git_odb::Write;
+        let dir = std::env::temp_dir().join(format!("git-remote-s3-pack-{}", std::process::id()));
+        let loose_dir = dir.join("loose");
+        let packed_dir = dir.join("packed");
+        fs::create_dir_all(&loose_dir).unwrap();
+        fs::create_dir_all(packed_dir.join("pack")).unwrap();
+
+        // Larger than the copy buffer, so it has to go through in several pieces
+        let big: Vec<u8> = (0..COPY_BUFFER_SIZE * 3 + 7).map(|i| (i % 251) as u8).collect();
+        let small = b"small".to_vec();
+        let db = git_odb::compound::Db::at(&loose_dir).unwrap();
+        let ids: Vec<ObjectId> = [&big, &small].iter()
+            .map(|data| db.write_stream(Kind::Blob, data.len() as u64, &data[..], git_hash::Kind::Sha1).unwrap())
+            .collect();
+
+        // Pack the loose objects, then pack them again out of that pack
+        let first = packed_dir.join("pack").join("pack-first.pack");
+        let (hash, index) = write_pack(&db, &ids, flate2::Compression::fast(), &first).unwrap();
+        fs::write(first.with_extension("idx"), index).unwrap();
+        let packed = git_odb::compound::Db::at(&packed_dir).unwrap();
+        assert_eq!(pack_trailer(&first).unwrap(), Some(hash));
+        let second = dir.join("pack-second.pack");
+        let (_, index) = write_pack(&packed, &ids, flate2::Compression::none(), &second).unwrap();
+        fs::write(second.with_extension("idx"), index).unwrap();
+        // Deflate the stored entries again, as after a download
+        let third = dir.join("pack-third.pack");
+        let (_, index) = rewrite_pack(&second, &second.with_extension("idx"), flate2::Compression::fast(), &third).unwrap();
+        fs::write(third.with_extension("idx"), index).unwrap();
+        assert!(fs::metadata(&third).unwrap().len() < fs::metadata(&second).unwrap().len());
+
+        for path in &[first, second, third] {
+            let bundle = pack::Bundle::at(path.with_extension("idx")).unwrap();
+            bundle.pack.verify_checksum(progress::Discard).unwrap();
+            bundle.index.verify_checksum(progress::Discard).unwrap();
+            let mut buf = Vec::new();
+            for (id, data) in ids.iter().zip(&[&big, &small]) {
+                let obj = bundle.find(id, &mut buf, &mut pack::cache::Never).unwrap().unwrap();
+                assert_eq!(obj.kind, Kind::Blob);
+                assert_eq!(obj.data, &data[..]);
+            }
+        }
+        fs::remove_dir_all(&dir).ok();
+    }
+    #[test]
+    fn test_pack_index_large_offsets() {
+        let id = |byte: u8| ObjectId::from_20_bytes(&[byte; 20]);
+        let entries = vec![
+            (id(3), 3, 0x1_2345_6789),
+            (id(1), 1, 12),
+            (id(2), 2, 0x8000_0000),
+            (id(4), 4, 0x7fff_ffff),
+        ];
+        let pack_hash = id(0xaa);
+        let path = std::env::temp_dir().join(format!("git-remote-s3-index-{}.idx", std::process::id()));
+        fs::write(&path, pack_index(entries, &pack_hash)).unwrap();
+        let index = pack::index::File::at(&path).unwrap();
+        fs::remove_file(&path).ok();
+
+        index.verify_checksum(progress::Discard).unwrap();
+        assert_eq!(index.num_objects(), 4);
+        assert_eq!(index.pack_checksum(), pack_hash);
+        for (byte, offset) in &[(1, 12), (2, 0x8000_0000), (3, 0x1_2345_6789), (4, 0x7fff_ffff)] {
+            let i = index.lookup(id(*byte)).unwrap();
+            assert_eq!(index.pack_offset_at_index(i), *offset);
+            assert_eq!(index.crc32_at_index(i), Some(*byte as u32));
+        }
+    }
+}
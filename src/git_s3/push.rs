@@ -1,13 +1,120 @@
 use super::remote::Remote;
 use super::cmd;
+use super::refs::{Manifest, RemoteRef};
+use super::util::parallel_map;
 
 use log::{info, trace, debug};
 use anyhow::{Context, Error, Result};
-use std::fs;
+use std::collections::{BinaryHeap, HashMap, HashSet};
+use std::collections::hash_map::Entry;
+use std::fmt;
+
+use git_object::Kind;
+use git_object::tree::EntryMode;
+use git_object::immutable::{Commit, Tag, Tree};
 
-use git_object::immutable::{Commit, Tree};
 use git_hash::ObjectId;
 
+/// A single `[+]<src>:<dst>` refspec from a push batch
+#[derive(Debug, PartialEq)]
+pub struct PushSpec {
+    /// Local ref to push, empty to delete `dst`
+    pub src: String,
+    pub dst: String,
+    pub force: bool,
+}
+
+impl PushSpec {
+    /// Parse the argument of a push command
+    pub fn parse(arg: &str) -> Result<Self> {
+        let push_err = "Push command has invalid arg";
+        let (src, force) = match arg.strip_prefix('+') {
+            Some(s) => (s, true),
+            None => (arg, false),
+        };
+        let mut colon_iter = src.splitn(2, ':');
+        let src = colon_iter.next()
+            .ok_or_else(|| Error::msg(format!("{} from src parsing: {}", push_err, arg)))?;
+        let dst = colon_iter.next()
+            .filter(|s| !s.is_empty())
+            .ok_or_else(|| Error::msg(format!("{} from dst parsing: {}", push_err, arg)))?;
+        Ok(PushSpec { src: src.to_string(), dst: dst.to_string(), force })
+    }
+    /// Whether this deletes the remote ref instead of updating it
+    pub fn is_delete(&self) -> bool {
+        self.src.is_empty()
+    }
+}
+
+/// Reasons a ref update is refused. Their display strings are the ones git recognises in an
+/// `error <dst> <why>` line, so it can explain the rejection to the user
+#[derive(Debug, Clone, PartialEq)]
+pub enum Rejection {
+    /// The remote ref isn't an ancestor of the pushed commit
+    NonFastForward,
+    /// The remote ref points at a commit we don't have locally
+    FetchFirst,
+    /// Deleting the branch the remote HEAD points to, without forcing it
+    DeleteCurrent,
+    /// Moving an existing tag without forcing it
+    AlreadyExists,
+}
+
+impl fmt::Display for Rejection {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Rejection::NonFastForward => write!(f, "non-fast forward"),
+            Rejection::FetchFirst => write!(f, "fetch first"),
+            Rejection::DeleteCurrent => write!(f, "deletion of the current branch prohibited"),
+            Rejection::AlreadyExists => write!(f, "already exists"),
+        }
+    }
+}
+
+impl std::error::Error for Rejection {}
+
+/// Format the status line git expects for the outcome of pushing to `dst`
+pub fn status_line(dst: &str, result: &Result<()>) -> String {
+    match result {
+        Ok(()) => format!("ok {}", dst),
+        Err(e) => match e.downcast_ref::<Rejection>() {
+            Some(rejection) => format!("error {} {}", dst, rejection),
+            // Whole context chain on a single line
+            None => format!("error {} {}", dst, format!("{:#}", e).replace('\n', " ")),
+        },
+    }
+}
+
+/// An object id along with the kind of object it is
+type Typed = (ObjectId, Kind);
+
+/// Times to retry a manifest write that raced with another push to different refs
+const MANIFEST_RETRIES: usize = 5;
+
+/// Copy an error that applies to several refs. Rejections keep their type so git still sees
+/// the reason
+fn share_error(e: &Error) -> Error {
+    match e.downcast_ref::<Rejection>() {
+        Some(rejection) => Error::new(rejection.clone()),
+        None => Error::msg(format!("{:#}", e)),
+    }
+}
+
+/// Fail every ref update that is still going ahead with `e`
+fn fail_accepted(results: &mut [(String, Result<RefUpdate>)], e: &Error) {
+    for (_, result) in results.iter_mut().filter(|(_, result)| result.is_ok()) {
+        *result = Err(share_error(e));
+    }
+}
+
+/// A checked ref update, applied once the objects it needs are on the remote
+struct RefUpdate {
+    /// Commit to point the ref at, `None` to delete it
+    new: Option<ObjectId>,
+    /// The remote ref when it was checked, `None` if it didn't exist
+    old: Option<RemoteRef>,
+}
+
 impl Remote {
     /*
      * push +<src>:<dst>
@@ -20,199 +127,504 @@ impl Remote {
      * branch, and the second asking to push ref foo to ref bar (forced update requested by the
      * +).
      *
+     * An empty <src> deletes the remote <dst> ref.
+     *
      * Needed by push
      */
-    // Order of uploads should be blob -> tree -> commits -> refs
-    // i.e. small atomic objects first, nested objects and references last
-    pub fn push(&self, src_string: &str, dst_string: &str, force_push: bool) -> Result<()> {
+    /// Push a whole batch, returning the outcome for each `dst`. Everything the accepted refs
+    /// need goes up as a single pack before any ref is updated, and a failing ref doesn't stop
+    /// the rest of the batch
+    // Order of uploads should be pack -> refs
+    // i.e. objects first, references last
+    pub async fn push(&self, specs: &[PushSpec]) -> Vec<(String, Result<()>)> {
+        // Refs live either in the manifest, or in an object each. Either way, updating them is
+        // only safe if the bucket honours conditional writes. A dry run writes nothing, not even
+        // the check
+        let manifest = match async {
+            if !self.options.dry_run {
+                self.check_conditional_writes().await?;
+            }
+            self.read_manifest().await
+        }.await {
+            Ok(manifest) => manifest,
+            Err(e) => return specs.iter()
+                .map(|spec| (spec.dst.clone(), Err(share_error(&e))))
+                .collect(),
+        };
+
+        // Resolve and check every ref before uploading anything
+        let mut results: Vec<(String, Result<RefUpdate>)> = Vec::new();
+        for spec in specs {
+            debug!("Pushing {} to {} {}", spec.src, spec.dst, if spec.force {"forcefully"} else {""});
+            results.push((spec.dst.clone(), self.check_push(spec, manifest.as_ref()).await));
+        }
+
+        // An atomic push goes through whole or not at all. Only a manifest can be updated at once
+        if self.options.atomic {
+            if manifest.is_none() {
+                fail_accepted(&mut results, &Error::msg("atomic push needs a ref manifest, see --init-manifest"));
+            } else if results.iter().any(|(_, result)| result.is_err()) {
+                fail_accepted(&mut results, &Error::msg("atomic push failed"));
+            }
+        }
+
+        // Push the accepted commits and everything they need that isn't reachable from a remote
+        // ref as a single pack
+        let tips: Vec<ObjectId> = results.iter()
+            .filter_map(|(_, result)| result.as_ref().ok()?.new)
+            .collect();
+        if !tips.is_empty() {
+            if let Err(e) = self.upload_missing(&tips).await {
+                // Every accepted ref depends on the pack
+                fail_accepted(&mut results, &e);
+            }
+        }
+
+        // Finally, update the refs
+        let results: Vec<(String, Result<()>)> = match manifest {
+            Some(manifest) => self.update_manifest(manifest, results).await,
+            // Each ref is its own object, they can all be written at once
+            None => parallel_map(results, self.parallelism, |(dst, result)| async move {
+                let result = match result {
+                    Ok(update) => self.update_ref(&dst, update).await,
+                    Err(e) => Err(e),
+                };
+                Ok((dst, result))
+            }).await.expect("ref updates report errors in their results"),
+        };
+        for (dst, result) in results.iter() {
+            if let Err(e) = result {
+                info!("Push to {} failed: {:?}", dst, e);
+            }
+        }
+
+        // The first push decides the default branch
+        let pushed: Vec<(&str, &str)> = specs.iter().zip(results.iter())
+            .filter(|(spec, (_, result))| !spec.is_delete() && result.is_ok())
+            .map(|(spec, (dst, _))| (spec.src.as_str(), dst.as_str()))
+            .collect();
+        self.init_remote_head(&pushed).await;
+        results
+    }
+
+    /// Current value of remote ref `name`, from the manifest if the bucket has one
+    async fn current_ref(&self, name: &str, manifest: Option<&Manifest>) -> Result<Option<RemoteRef>> {
+        match manifest {
+            Some(manifest) => Ok(manifest.refs.get(name).map(|sha| RemoteRef {
+                sha: sha.clone(),
+                etag: manifest.etag.clone().unwrap_or_default(),
+            })),
+            None => self.read_remote_ref(name).await,
+        }
+    }
+
+    /// Resolve the local commit of a push, and make sure it can replace the remote ref
+    async fn check_push(&self, spec: &PushSpec, manifest: Option<&Manifest>) -> Result<RefUpdate> {
+        if spec.is_delete() {
+            return self.check_delete(spec, manifest).await
+        }
+        let src_string = &spec.src;
+        let dst_string = &spec.dst;
         // Read local ref
         trace!("Reading local ref");
-        // Build path
-        let mut path = self.git_dir.clone(); path.push(&src_string);
-        if !path.exists() {
-            return Err(Error::msg(format!("Unable to find local ref for {}", &src_string)))
+        let push_id = self.resolve_local_ref(src_string)
+            .with_context(|| format!("Unable to resolve local ref {}", src_string))?;
+        if !self.has_object(&push_id) {
+            return Err(Error::msg(format!("Object {} for {} not found in database", push_id, src_string)))
         }
-
-        let push_sha = fs::read_to_string(path).with_context(|| format!("Unable to read ref {}", &src_string))?;
-        let push_sha = push_sha.trim();
+        let push_sha = push_id.to_sha1_hex_string();
+        let push_sha = push_sha.as_str();
         trace!("Local ref: {} to {}", &src_string, push_sha);
+        let force_push = spec.force || self.options.force;
 
-        // Push this commit and all deps
-        self.upload_commit(push_sha)
-            .with_context(|| format!("Unable to upload commit for {}", &src_string))?;
-
-        // Finally, update the ref
-        // Verify it's a fast forward. Get remote ref, return err if err or non-okay code
-        let remote_exists = match self.bucket
-            .get_object_blocking(dst_string.to_string())
-            .with_context(|| format!("Error doing get for remote ref {}", dst_string)) {
-                Ok((data, code)) if code == 200 => Ok((data, code)),
-                Ok(_) => Err(Error::msg("Non-okay get for remote ref")),
-                Err(e) => Err(e),
-            };
+        // Verify it's a fast forward. The ref is only written if it still matches what was
+        // checked here
+        let old = self.current_ref(dst_string, manifest).await?;
         // If exists, check fast forward
-        if let Ok(remote_exists) = remote_exists {
+        if let Some(old) = &old {
             debug!("Remote ref already exits");
-            let (data, _) = remote_exists;
-            let old_hash = std::str::from_utf8(&data)
-                .context("Unable to convert remote ref to str")?;
-            let is_ff = cmd::is_ancestor(&self.git_dir, old_hash, push_sha)
-                .context("Unable to check is ancestor for fast-forward")?;
+            let old_hash = old.sha.as_str();
+            // Can't tell if it's a fast-forward without having the remote commit
+            let old_id = ObjectId::from_hex(old_hash.as_bytes())
+                .with_context(|| format!("Unable to load remote ref {} into ObjectId", old_hash))?;
+            // Tags don't move, whatever they point to
+            if dst_string.starts_with("refs/tags/") && old_id != push_id && !force_push {
+                info!("Tag {} already exists", dst_string);
+                return Err(Error::new(Rejection::AlreadyExists));
+            }
+            if !self.has_object(&old_id) && !force_push {
+                info!("Remote ref {} is at unknown commit {}", dst_string, old_hash);
+                return Err(Error::new(Rejection::FetchFirst));
+            }
+            // Only commits can be fast-forwarded
+            let is_ff = self.has_object(&old_id)
+                && self.object_kind(&old_id)? == Kind::Commit
+                && self.object_kind(&push_id)? == Kind::Commit
+                && cmd::is_ancestor(&self.git_dir, old_hash, push_sha)
+                    .context("Unable to check is ancestor for fast-forward")?;
             if !is_ff {
                 info!("{} is not ff to {}", push_sha, old_hash);
             } else {
                 info!("{} is ff to {}", push_sha, old_hash);
             }
             if !is_ff && !force_push {
-                return Err(Error::msg(format!("{} is not fast-forward for {}", push_sha, old_hash)));
+                return Err(Error::new(Rejection::NonFastForward));
             }
         } else {
             info!("Pushing new ref {}", dst_string);
         }
+        Ok(RefUpdate { new: Some(push_id), old })
+    }
 
-        // Otherwise just update
-        info!("Updating {} to {}", dst_string, push_sha);
-        let (_, code) = self.bucket
-            .put_object_blocking(dst_string.to_string(), push_sha.as_bytes())
-            .with_context(|| format!("Unable to update ref {}", dst_string))?;
-        match code {
-            200 => Ok(()),
-            _ => Err(Error::msg(format!("Non-okay push for \'{}\': {}", push_sha, code))),
+    /// Make sure the remote ref to delete exists, and that it isn't the remote HEAD unless the
+    /// deletion is forced
+    async fn check_delete(&self, spec: &PushSpec, manifest: Option<&Manifest>) -> Result<RefUpdate> {
+        let old = self.current_ref(&spec.dst, manifest).await?
+            .ok_or_else(|| Error::msg(format!("Unable to delete {}: no such remote ref", spec.dst)))?;
+        let force_push = spec.force || self.options.force;
+        if !force_push && self.remote_head().await?.as_deref() == Some(spec.dst.as_str()) {
+            info!("Refusing to delete remote HEAD {}", spec.dst);
+            return Err(Error::new(Rejection::DeleteCurrent))
         }
+        info!("Deleting remote ref {}", spec.dst);
+        Ok(RefUpdate { new: None, old: Some(old) })
     }
 
-    /// Check if a passed sha exists in the configured bucket
-    fn check_hash_remote(&self, sha1: String) -> Result<bool> {
-        let results = self.bucket.list_blocking(sha1.to_string(), None)
-            .with_context(|| format!("Check existence of remote object {} failed", &sha1))?;
-        trace!("Results of list is {:?}", &results);
-        for (r, code) in results {
-            if code != 200 {
-                return Err(Error::msg(format!("Non-okay list for \'{}\': {}", &sha1, code)))
+    /// Upload everything reachable from `tips` that the remote refs don't already reach
+    async fn upload_missing(&self, tips: &[ObjectId]) -> Result<()> {
+        let remote_tips: Vec<ObjectId> = self.remote_refs().await
+            .context("Unable to read remote refs")?
+            .iter()
+            .filter_map(|(_, sha)| ObjectId::from_hex(sha.as_bytes()).ok())
+            .collect();
+        let missing = self.objects_to_push(tips, &remote_tips)
+            .context("Unable to find objects to push")?;
+        if self.options.dry_run {
+            info!("Dry run, not uploading {} objects", missing.len());
+        } else if !missing.is_empty() {
+            self.upload_pack(&missing).await
+                .context("Unable to upload pack")?;
+        }
+        Ok(())
+    }
+
+    /// Apply a checked update to remote ref `dst_string`. Fails as a non-fast forward if the ref
+    /// changed since it was checked
+    async fn update_ref(&self, dst_string: &str, update: RefUpdate) -> Result<()> {
+        let new_sha = update.new.map(|id| id.to_sha1_hex_string());
+        if self.options.dry_run {
+            info!("Dry run, not updating {} to {:?}", dst_string, new_sha);
+            return Ok(())
+        }
+        self.write_remote_ref(dst_string, new_sha.as_deref(), update.old.as_ref()).await
+    }
+
+    /// Apply every accepted update in a single manifest write. If the manifest changed since it
+    /// was read, the write is retried on the new version. Refs that moved meanwhile are rejected,
+    /// and unless the push is atomic the rest still go ahead
+    async fn update_manifest(&self, mut manifest: Manifest, mut results: Vec<(String, Result<RefUpdate>)>) -> Vec<(String, Result<()>)> {
+        let accepted = results.iter().filter(|(_, result)| result.is_ok()).count();
+        let mut pending = accepted > 0;
+        if pending && self.options.dry_run {
+            info!("Dry run, not updating {} refs in manifest", accepted);
+            pending = false;
+        }
+        let mut retries = 0;
+        while pending {
+            if retries == MANIFEST_RETRIES {
+                fail_accepted(&mut results, &Error::new(Rejection::NonFastForward));
+                break
+            }
+            retries += 1;
+            for (dst, result) in results.iter() {
+                match result {
+                    Ok(RefUpdate { new: Some(id), .. }) => manifest.refs.insert(dst.to_string(), id.to_sha1_hex_string()),
+                    Ok(RefUpdate { new: None, .. }) => manifest.refs.remove(dst.as_str()),
+                    Err(_) => continue,
+                };
+            }
+            match self.write_manifest(&manifest).await {
+                Ok(()) => break,
+                Err(e) if e.downcast_ref::<Rejection>().is_some() => (),
+                Err(e) => {
+                    fail_accepted(&mut results, &e);
+                    break
+                },
             }
-            trace!("Result in check is {:?}", r);
-            if !r.contents.is_empty() {
-                debug!("Object {} exists remotely, exitting", &sha1);
-                return Ok(true)
+            // Someone else wrote the manifest. Fine, unless they moved one of our refs
+            manifest = match self.read_manifest().await
+                .and_then(|manifest| manifest.ok_or_else(|| Error::msg("Ref manifest was removed"))) {
+                Ok(manifest) => manifest,
+                Err(e) => {
+                    fail_accepted(&mut results, &e);
+                    break
+                },
+            };
+            let moved = |dst: &str, update: &RefUpdate| {
+                manifest.refs.get(dst) != update.old.as_ref().map(|old| &old.sha)
+            };
+            if self.options.atomic {
+                if results.iter().any(|(dst, result)| matches!(result, Ok(update) if moved(dst, update))) {
+                    fail_accepted(&mut results, &Error::new(Rejection::NonFastForward));
+                }
+            } else {
+                for (dst, result) in results.iter_mut() {
+                    if matches!(result, Ok(update) if moved(dst, update)) {
+                        info!("Remote ref {} changed since it was read", dst);
+                        *result = Err(Error::new(Rejection::NonFastForward));
+                    }
+                }
+            }
+            pending = results.iter().any(|(_, result)| result.is_ok());
+            if pending {
+                debug!("Ref manifest changed, retrying on version {}", manifest.version);
             }
         }
-        Ok(false)
+        results.into_iter()
+            .map(|(dst, result)| (dst, result.map(|_| ())))
+            .collect()
     }
 
-    /// Upload a commit if it doesn't exist remotely. Also verify all objects it describes exists
-    /// (parents, tree)
-    fn upload_commit(&self, sha1: &str) -> Result<()> {
-        // Load commit from sha
-        debug!("Uploading {}", &sha1);
+    /// Find every object reachable from `tips` but not from `uninteresting`, about the same set
+    /// as `git rev-list --objects <tips> ^<uninteresting>`. Only the trees of the uninteresting
+    /// commits the new ones build on are excluded, so objects brought back from further down
+    /// the history are pushed again
+    fn objects_to_push(&self, tips: &[ObjectId], uninteresting: &[ObjectId]) -> Result<Vec<ObjectId>> {
         let mut buf = Vec::new();
-        let id = ObjectId::from_hex(sha1.as_bytes()).context("Unable to load commit into ObjectId")?;
-        trace!("Object id is {:?}", id);
-        let new_obj = self.git_db.find(id, &mut buf, &mut git_odb::pack::cache::Never)
-            .with_context(|| "Unable to search local database")?;
-        let new_obj = match new_obj {
-            Some(s) => s,
-            None => return Err(Error::msg("object not found in database")),
+        // Tips we don't have locally can't be walked, and can't be excluded
+        let uninteresting: Vec<ObjectId> = uninteresting.iter()
+            .filter(|id| self.has_object(id))
+            .cloned()
+            .collect();
+        // Parsed commits, as (commit time, tree, parents)
+        let mut commits: HashMap<ObjectId, (u32, ObjectId, Vec<ObjectId>)> = HashMap::new();
+        let mut queue = BinaryHeap::new();
+
+        // Refs can point at tags, and tags at anything. Peel them to find the commits to walk
+        let (hidden_tags, hidden_peeled) = self.peel_tags(&uninteresting, &mut buf)?;
+        let (tip_tags, tip_peeled) = self.peel_tags(tips, &mut buf)?;
+        let commits_of = |peeled: &[Typed]| -> Vec<ObjectId> {
+            peeled.iter().filter(|(_, kind)| *kind == Kind::Commit).map(|(id, _)| *id).collect()
         };
+        let hidden_commits = commits_of(&hidden_peeled);
+        let tip_commits = commits_of(&tip_peeled);
+        let mut hidden: HashSet<ObjectId> = hidden_commits.iter().cloned().collect();
 
-        // Check if exists. If so, exit
-        if self.check_hash_remote(sha1.to_string())
-            .with_context(|| "Unable to check state of commit")? {
-            return Ok(())
+        // Walk commits newest first, hiding the parents of hidden commits. Once only hidden
+        // commits are queued, nothing left can be reachable from `tips` alone
+        let mut queued = HashSet::new();
+        let mut interesting = 0;
+        for id in hidden_commits.iter().chain(tip_commits.iter()) {
+            if let Entry::Vacant(entry) = commits.entry(*id) {
+                let commit = self.read_commit(*id, &mut buf)?;
+                queue.push((commit.0, *id));
+                queued.insert(*id);
+                if !hidden.contains(id) {
+                    interesting += 1;
+                }
+                entry.insert(commit);
+            }
         }
+        // Hidden commits no older than an interesting one walked can still hide it, as commits
+        // made in the same second share a time
+        let mut oldest = None;
+        let mut walked = Vec::new();
+        let can_hide = |queue: &BinaryHeap<(u32, ObjectId)>, oldest: Option<u32>| {
+            matches!((queue.peek(), oldest), (Some((time, _)), Some(oldest)) if *time >= oldest)
+        };
+        while interesting > 0 || can_hide(&queue, oldest) {
+            let (time, id) = queue.pop().expect("queue is not empty");
+            queued.remove(&id);
+            walked.push(id);
+            let is_hidden = hidden.contains(&id);
+            if !is_hidden {
+                interesting -= 1;
+                oldest = Some(time);
+            }
+            let parents = commits[&id].2.clone();
+            for parent in parents {
+                if is_hidden {
+                    // Hide everything already walked below this parent too, in case commit
+                    // times are skewed
+                    let mut to_hide = vec![parent];
+                    while let Some(hide) = to_hide.pop() {
+                        if hidden.insert(hide) {
+                            if queued.contains(&hide) {
+                                interesting -= 1;
+                            }
+                            if let Some((_, _, grandparents)) = commits.get(&hide) {
+                                to_hide.extend(grandparents.iter().cloned());
+                            }
+                        }
+                    }
+                }
+                if let Entry::Vacant(entry) = commits.entry(parent) {
+                    let commit = self.read_commit(parent, &mut buf)?;
+                    queue.push((commit.0, parent));
+                    queued.insert(parent);
+                    if !hidden.contains(&parent) {
+                        interesting += 1;
+                    }
+                    entry.insert(commit);
+                }
+            }
+        }
+        let walked: Vec<ObjectId> = walked.into_iter().filter(|id| !hidden.contains(id)).collect();
 
-        // Parse the object
-        let commit_obj = Commit::from_bytes(new_obj.data)
-            .with_context(|| "Unable to parse commit")?;
-        // Upload commit
-
-        // Parse tree, fetch deps
-        self.upload_tree(&commit_obj.tree().to_sha1_hex_string())
-            .with_context(|| "Unable to upload tree")?;
-        commit_obj.parents()
-            .try_for_each(|obj| self.upload_commit(&obj.to_sha1_hex_string()))
-            .with_context(|| format!("Unable to fetch parent for commit \'{}\'", &sha1))?;
-
-        // Now upload
-        let (_, code) = self.bucket.put_object_blocking(&sha1, &new_obj.data)
-            .context("Unable to upload commit")?;
-        match code {
-            200 => Ok(()),
-            _ => Err(Error::msg(format!("Non-okay push for commit \'{}\': {}", &sha1, code))),
+        // Everything in the trees of the hidden commits the pushed ones build on is already on
+        // the remote, as are the remote tags
+        let mut seen: HashSet<ObjectId> = hidden_tags.into_iter().collect();
+        let boundary: HashSet<ObjectId> = walked.iter()
+            .flat_map(|id| commits[id].2.iter())
+            .filter(|parent| hidden.contains(*parent))
+            .cloned()
+            .collect();
+        for id in boundary {
+            self.walk_tree(commits[&id].1, &mut seen, &mut Vec::new())?;
         }
+        let mut objects: Vec<ObjectId> = tip_tags.into_iter()
+            .filter(|id| seen.insert(*id))
+            .collect();
+        for id in walked {
+            objects.push(id);
+            self.walk_tree(commits[&id].1, &mut seen, &mut objects)?;
+        }
+        for (id, kind) in tip_peeled.iter() {
+            match kind {
+                Kind::Tree => self.walk_tree(*id, &mut seen, &mut objects)?,
+                Kind::Blob if seen.insert(*id) => objects.push(*id),
+                _ => (),
+            }
+        }
+        debug!("Found {} objects to push", objects.len());
+        Ok(objects)
     }
-    /// Upload a tree if it doesn't exist remotely. Also verify all objects it describes exists
-    /// (subtrees, blobs)
-    fn upload_tree(&self, sha1: &str) -> Result<()> {
-        debug!("Uploading tree {}", &sha1);
-        // Load tree from sha
-        let mut buf = Vec::new();
-        let id = ObjectId::from_hex(sha1.as_bytes()).context("Unable to load tree into ObjectId")?;
-        trace!("Object id is {:?}", id);
-        let new_obj = self.git_db.find(id, &mut buf, &mut git_odb::pack::cache::Never)
-            .with_context(|| "Unable to search local database")?;
-        let new_obj = match new_obj {
-            Some(s) => s,
-            None => return Err(Error::msg("object not found in database")),
-        };
 
-        // Check if exists. If so, exit
-        if self.check_hash_remote(sha1.to_string())
-            .with_context(|| "Unable to check state of tree")? {
-            return Ok(())
+    /// Follow tags down to the objects they point at. Returns every tag passed through, and the
+    /// non-tag objects reached with their kinds
+    fn peel_tags(&self, ids: &[ObjectId], buf: &mut Vec<u8>) -> Result<(Vec<ObjectId>, Vec<Typed>)> {
+        let mut tags = Vec::new();
+        let mut peeled = Vec::new();
+        for id in ids {
+            let mut id = *id;
+            loop {
+                let obj = self.git_db.find(id, buf, &mut git_odb::pack::cache::Never)
+                    .context("Unable to search local database")?
+                    .ok_or_else(|| Error::msg(format!("Object {} not found in database", id)))?;
+                if obj.kind != Kind::Tag {
+                    peeled.push((id, obj.kind));
+                    break
+                }
+                tags.push(id);
+                id = Tag::from_bytes(obj.data)
+                    .with_context(|| format!("Unable to parse tag \'{}\'", id))?
+                    .target();
+            }
         }
+        Ok((tags, peeled))
+    }
 
-        // Parse the object
-        let tree_obj = Tree::from_bytes(new_obj.data)?;
-        trace!("Searching for children of {}", &sha1);
-        // Iter over entries, fetch tree or object
-        tree_obj.entries.iter()
-            .try_for_each(|e| {
-                 let sha1_bytes = e.oid.to_sha1_hex();
-                 let sha1 = std::str::from_utf8(&sha1_bytes)
-                     .context("Unable to parse sha from child of tree")?;
-                 if e.mode.is_tree() {
-                     self.upload_tree(sha1)
-                 } else {
-                     match self.upload_blob(sha1) {
-                         Ok(_) => Ok(()),
-                         Err(error) => Err(error),
-                     }
-                 }
-            })
-            .with_context(|| format!("Unable to push entries for tree \'{}\'", &sha1))?;
-        // Now upload
-        let (_, code) = self.bucket.put_object_blocking(&sha1, &new_obj.data)
-            .context("Unable to upload tree")?;
-        match code {
-            200 => Ok(()),
-            _ => Err(Error::msg(format!("Non-okay push for tree \'{}\': {}", &sha1, code))),
-        }
-    }
-    /// Upload a blob if it doesn't exist remotely
-    fn upload_blob(&self, sha1: &str) -> Result<()> {
-        debug!("Uploading blob {}", &sha1);
-        // Load blob from sha
+    /// Kind of an object in the local database
+    fn object_kind(&self, id: &ObjectId) -> Result<Kind> {
         let mut buf = Vec::new();
-        let id = ObjectId::from_hex(sha1.as_bytes()).context("Unable to load tree into ObjectId")?;
-        trace!("Object id is {:?}", id);
-        let new_obj = self.git_db.find(id, &mut buf, &mut git_odb::pack::cache::Never)
-            .with_context(|| "Unable to search local database")?;
-        let new_obj = match new_obj {
-            Some(s) => s,
-            None => return Err(Error::msg("object not found in database")),
-        };
+        let obj = self.git_db.find(id, &mut buf, &mut git_odb::pack::cache::Never)
+            .context("Unable to search local database")?
+            .ok_or_else(|| Error::msg(format!("Object {} not found in database", id)))?;
+        Ok(obj.kind)
+    }
 
-        // Check if exists. If so, exit
-        if self.check_hash_remote(sha1.to_string())
-            .context("Unable to check state of commit")? {
-            return Ok(())
-        }
-        // Otherwise, upload
-        let (_, code) = self.bucket.put_object_blocking(&sha1, &new_obj.data)
-            .context("Unable to upload blob")?;
-        match code {
-            200 => Ok(()),
-            _ => Err(Error::msg(format!("Non-okay push for blob \'{}\': {}", &sha1, code))),
+    /// Load a commit from the local database, returning its commit time, tree and parents
+    fn read_commit(&self, id: ObjectId, buf: &mut Vec<u8>) -> Result<(u32, ObjectId, Vec<ObjectId>)> {
+        let obj = self.git_db.find(id, buf, &mut git_odb::pack::cache::Never)
+            .context("Unable to search local database")?
+            .ok_or_else(|| Error::msg(format!("Commit {} not found in database", id)))?;
+        let commit_obj = Commit::from_bytes(obj.data)
+            .with_context(|| format!("Unable to parse commit \'{}\'", id))?;
+        Ok((commit_obj.committer.time.time, commit_obj.tree(), commit_obj.parents().collect()))
+    }
+
+    /// Collect every object under `tree` not in `seen` into `objects`, adding them to `seen`.
+    /// Subtrees already seen are skipped entirely. Submodule commits aren't ours to push
+    fn walk_tree(&self, tree: ObjectId, seen: &mut HashSet<ObjectId>, objects: &mut Vec<ObjectId>) -> Result<()> {
+        let mut buf = Vec::new();
+        let mut queue = vec![tree];
+        while let Some(id) = queue.pop() {
+            if !seen.insert(id) {
+                continue
+            }
+            objects.push(id);
+            let obj = self.git_db.find(id, &mut buf, &mut git_odb::pack::cache::Never)
+                .context("Unable to search local database")?
+                .ok_or_else(|| Error::msg(format!("Tree {} not found in database", id)))?;
+            let tree_obj = Tree::from_bytes(obj.data)
+                .with_context(|| format!("Unable to parse tree \'{}\'", id))?;
+            for e in tree_obj.entries.iter() {
+                if e.mode.is_tree() {
+                    queue.push(e.oid.to_owned());
+                } else if e.mode != EntryMode::Commit && seen.insert(e.oid.to_owned()) {
+                    objects.push(e.oid.to_owned());
+                }
+            }
         }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::git_s3::remote::TestRepo;
+
+    #[test]
+    fn test_parse_push_spec() {
+        let spec = PushSpec::parse("+refs/heads/main:refs/heads/other").unwrap();
+        assert_eq!(spec, PushSpec {
+            src: "refs/heads/main".to_string(),
+            dst: "refs/heads/other".to_string(),
+            force: true,
+        });
+        assert!(!PushSpec::parse("refs/heads/main:refs/heads/main").unwrap().force);
+    }
+    #[test]
+    fn test_parse_delete_push_spec() {
+        let spec = PushSpec::parse(":refs/heads/main").unwrap();
+        assert!(spec.is_delete());
+        assert_eq!(spec.dst, "refs/heads/main");
+        assert!(!PushSpec::parse("refs/heads/main:refs/heads/main").unwrap().is_delete());
+    }
+    #[test]
+    fn test_parse_invalid_push_spec() {
+        assert!(PushSpec::parse("refs/heads/main").is_err());
+        assert!(PushSpec::parse("refs/heads/main:").is_err());
+    }
+    #[test]
+    fn test_status_line() {
+        assert_eq!(status_line("refs/heads/main", &Ok(())), "ok refs/heads/main");
+        let rejected = Err(Error::new(Rejection::NonFastForward));
+        assert_eq!(status_line("refs/heads/main", &rejected), "error refs/heads/main non-fast forward");
+        let failed = Err(Error::msg("Non-okay push").context("Unable to upload pack"));
+        assert_eq!(status_line("refs/heads/main", &failed),
+            "error refs/heads/main Unable to upload pack: Non-okay push");
+    }
+    #[test]
+    fn test_objects_to_push() {
+        let repo = TestRepo::new("objects-to-push");
+        let first = repo.commit("a", "a");
+        let second = repo.commit("b", "b");
+        let remote = repo.remote();
+        let sorted = |mut ids: Vec<ObjectId>| { ids.sort(); ids };
+
+        // Only the new commit, its tree and the blob it adds
+        let objects = remote.objects_to_push(&[second], &[first]).unwrap();
+        assert_eq!(sorted(objects), sorted(vec![second, repo.id("HEAD^{tree}"), repo.id("HEAD:b")]));
+        // Everything for a new remote
+        assert_eq!(remote.objects_to_push(&[second], &[]).unwrap().len(), 6);
+        // Nothing when the remote already has the tip
+        assert!(remote.objects_to_push(&[first], &[second]).unwrap().is_empty());
+        assert!(remote.objects_to_push(&[second], &[second]).unwrap().is_empty());
+        // Remote tips we don't have are ignored
+        let unknown = ObjectId::from_hex(b"0123456789012345678901234567890123456789").unwrap();
+        let objects = remote.objects_to_push(&[second], &[unknown, first]).unwrap();
+        assert_eq!(objects.len(), 3);
     }
 }
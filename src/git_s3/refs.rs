@@ -0,0 +1,283 @@
+use super::push::Rejection;
+use super::remote::Remote;
+use super::util::parallel_map;
+
+use log::{info, trace, debug};
+use anyhow::{Context, Error, Result};
+use std::collections::BTreeMap;
+
+/// Times to retry reading an object that keeps changing under us
+const READ_RETRIES: usize = 5;
+
+/// Key written to check that the bucket honours conditional writes. It is only ever created in
+/// buckets that don't
+const CONDITIONAL_CHECK_KEY: &str = "conditional-write-check";
+
+/// Key of an empty object marking that the bucket passed the conditional write check
+const CONDITIONAL_MARKER_KEY: &str = "conditional-writes";
+
+/// Key of the manifest holding every ref, for buckets storing refs in a single object
+pub const MANIFEST_KEY: &str = "refs.manifest";
+
+/// A remote ref along with the ETag of the object holding it. Writes made with it only succeed
+/// if the ref hasn't changed since it was read
+#[derive(Debug, Clone)]
+pub struct RemoteRef {
+    /// Hex sha the ref points to
+    pub sha: String,
+    /// ETag of the ref object when `sha` was read
+    pub etag: String,
+}
+
+/// Every remote ref, stored as one object so several refs can be updated in a single write.
+/// Serialized as a `version <n>` line followed by a `<sha> <name>` line per ref
+#[derive(Debug, Default, PartialEq)]
+pub struct Manifest {
+    /// Bumped on every write
+    pub version: u64,
+    /// Ref names to the hex sha they point to
+    pub refs: BTreeMap<String, String>,
+    /// ETag of the manifest when it was read, `None` if it doesn't exist yet
+    pub etag: Option<String>,
+}
+
+impl Manifest {
+    /// Parse a manifest read from the bucket
+    pub fn parse(data: &str) -> Result<Self> {
+        let mut lines = data.lines();
+        let version = lines.next()
+            .and_then(|l| l.strip_prefix("version "))
+            .ok_or_else(|| Error::msg("Ref manifest has no version"))?;
+        let version = version.parse()
+            .with_context(|| format!("Invalid ref manifest version \'{}\'", version))?;
+        let mut refs = BTreeMap::new();
+        for line in lines.filter(|l| !l.is_empty()) {
+            let mut parts = line.splitn(2, ' ');
+            match (parts.next(), parts.next()) {
+                (Some(sha), Some(name)) => refs.insert(name.to_string(), sha.to_string()),
+                _ => return Err(Error::msg(format!("Invalid ref manifest line \'{}\'", line))),
+            };
+        }
+        Ok(Manifest { version, refs, etag: None })
+    }
+
+    /// Serialize the manifest to be written to the bucket
+    pub fn serialize(&self) -> String {
+        let mut data = format!("version {}\n", self.version);
+        for (name, sha) in self.refs.iter() {
+            data.push_str(&format!("{} {}\n", sha, name));
+        }
+        data
+    }
+}
+
+impl Remote {
+    /// Check that the bucket honours conditional writes, which every ref update relies on. A
+    /// write conditioned on an ETag that no object has is refused by stores that support them,
+    /// stores that ignore the condition let it through. A bucket that passes is marked, so later
+    /// pushes only look for the marker. One that doesn't is refused if conditional writes are
+    /// required, and warned about otherwise
+    pub async fn check_conditional_writes(&self) -> Result<()> {
+        let (_, code) = self.bucket().await.head_object(self.key(CONDITIONAL_MARKER_KEY)).await
+            .context("Unable to check for conditional writes")?;
+        if code == 200 {
+            debug!("Bucket is marked as honouring conditional writes");
+            return Ok(())
+        }
+        let mut bucket = self.bucket().await;
+        bucket.add_header("If-Match", "\"git-remote-s3-check\"");
+        let (_, code) = bucket.put_object(self.key(CONDITIONAL_CHECK_KEY), b"").await
+            .context("Unable to check for conditional writes")?;
+        debug!("Conditional write check: {}", code);
+        match code {
+            404 | 409 | 412 => {
+                // Only saves checking again, so failing to write it isn't an error
+                match self.bucket().await.put_object(self.key(CONDITIONAL_MARKER_KEY), b"").await {
+                    Ok((_, code)) => debug!("Put for conditional write marker: {}", code),
+                    Err(e) => debug!("Unable to mark conditional writes: {:?}", e),
+                }
+                Ok(())
+            },
+            200 => {
+                self.bucket().await.delete_object(self.key(CONDITIONAL_CHECK_KEY)).await
+                    .context("Unable to delete conditional write check")?;
+                let problem = "The bucket ignores conditional writes (If-Match), so concurrent \
+                    pushes could overwrite each other's refs";
+                if self.require_conditional_writes {
+                    return Err(Error::msg(format!("{}. Refusing to update refs", problem)))
+                }
+                eprintln!("warning: {}", problem);
+                Ok(())
+            },
+            _ => Err(Error::msg(format!("Non-okay conditional write check: {}", code))),
+        }
+    }
+
+    /// Read an object along with the ETag of the version read, returning `None` if it doesn't
+    /// exist
+    async fn read_with_etag(&self, key: &str) -> Result<Option<(Vec<u8>, String)>> {
+        for _ in 0..READ_RETRIES {
+            let (head, code) = self.bucket().await.head_object(self.key(key)).await
+                .with_context(|| format!("Unable to head \'{}\'", key))?;
+            match code {
+                200 => (),
+                404 => return Ok(None),
+                _ => return Err(Error::msg(format!("Non-okay head for \'{}\': {}", key, code))),
+            }
+            let etag = head.e_tag
+                .ok_or_else(|| Error::msg(format!("\'{}\' has no ETag", key)))?;
+
+            // Only read the content matching that ETag
+            let mut bucket = self.bucket().await;
+            bucket.add_header("If-Match", &etag);
+            let (data, code) = bucket.get_object(self.key(key)).await
+                .with_context(|| format!("Error doing get for \'{}\'", key))?;
+            match code {
+                200 => return Ok(Some((self.unseal(key, data)?, etag))),
+                // Changed or deleted between the two requests, try again
+                404 | 412 => debug!("\'{}\' changed while reading it", key),
+                _ => return Err(Error::msg(format!("Non-okay get for \'{}\': {}", key, code))),
+            }
+        }
+        Err(Error::msg(format!("\'{}\' kept changing while reading it", key)))
+    }
+
+    /// Read remote ref `name`, returning `None` if it doesn't exist
+    pub async fn read_remote_ref(&self, name: &str) -> Result<Option<RemoteRef>> {
+        let (data, etag) = match self.read_with_etag(name).await
+            .with_context(|| format!("Unable to read remote ref {}", name))? {
+            Some(read) => read,
+            None => return Ok(None),
+        };
+        let sha = std::str::from_utf8(&data)
+            .context("Unable to convert remote ref to str")?
+            .trim()
+            .to_string();
+        trace!("Remote ref {} is {} ({})", name, sha, etag);
+        Ok(Some(RemoteRef { sha, etag }))
+    }
+
+    /// Point remote ref `name` at `sha`, or delete it if `sha` is `None`. The write only goes
+    /// through if the ref still matches `expected`, or doesn't exist if `expected` is `None`. A
+    /// ref that changed since it was read is rejected as a non-fast forward
+    pub async fn write_remote_ref(&self, name: &str, sha: Option<&str>, expected: Option<&RemoteRef>) -> Result<()> {
+        let mut bucket = self.bucket().await;
+        match expected {
+            Some(expected) => bucket.add_header("If-Match", &expected.etag),
+            None => bucket.add_header("If-None-Match", "*"),
+        }
+        let (_, code) = match sha {
+            Some(sha) => {
+                info!("Updating {} to {}", name, sha);
+                bucket.put_object(self.key(name), &self.seal(name, sha.as_bytes())?).await
+                    .with_context(|| format!("Unable to update ref {}", name))?
+            },
+            None => {
+                info!("Deleting {}", name);
+                bucket.delete_object(self.key(name)).await
+                    .with_context(|| format!("Unable to delete ref {}", name))?
+            },
+        };
+        match code {
+            200 | 204 => Ok(()),
+            // Someone else updated the ref first. 409 is a conflicting conditional write in flight
+            409 | 412 => {
+                info!("Remote ref {} changed since it was read", name);
+                Err(Error::new(Rejection::NonFastForward))
+            },
+            _ => Err(Error::msg(format!("Non-okay write for ref \'{}\': {}", name, code))),
+        }
+    }
+
+    /// Read the ref manifest, returning `None` if the bucket stores one object per ref
+    pub async fn read_manifest(&self) -> Result<Option<Manifest>> {
+        let (data, etag) = match self.read_with_etag(MANIFEST_KEY).await
+            .context("Unable to read ref manifest")? {
+            Some(read) => read,
+            None => return Ok(None),
+        };
+        let mut manifest = Manifest::parse(std::str::from_utf8(&data)
+            .context("Unable to convert ref manifest to str")?)?;
+        trace!("Ref manifest is version {} ({})", manifest.version, etag);
+        manifest.etag = Some(etag);
+        Ok(Some(manifest))
+    }
+
+    /// Write the next version of the manifest. Only goes through if the manifest is unchanged
+    /// since it was read, otherwise fails as a non-fast forward
+    pub async fn write_manifest(&self, manifest: &Manifest) -> Result<()> {
+        let next = Manifest { version: manifest.version + 1, refs: manifest.refs.clone(), etag: None };
+        let mut bucket = self.bucket().await;
+        match &manifest.etag {
+            Some(etag) => bucket.add_header("If-Match", etag),
+            None => bucket.add_header("If-None-Match", "*"),
+        }
+        info!("Writing ref manifest version {}", next.version);
+        let data = self.seal(MANIFEST_KEY, next.serialize().as_bytes())?;
+        let (_, code) = bucket.put_object(self.key(MANIFEST_KEY), &data).await
+            .context("Unable to write ref manifest")?;
+        match code {
+            200 => Ok(()),
+            409 | 412 => {
+                info!("Ref manifest changed since it was read");
+                Err(Error::new(Rejection::NonFastForward))
+            },
+            _ => Err(Error::msg(format!("Non-okay write for ref manifest: {}", code))),
+        }
+    }
+
+    /// Move every ref into a new manifest, so multi-ref pushes can be applied atomically
+    pub async fn init_manifest(&self) -> Result<()> {
+        self.check_conditional_writes().await?;
+        if self.read_manifest().await?.is_some() {
+            return Err(Error::msg("Remote already stores refs in a manifest"))
+        }
+        // Read every ref along with its ETag, so none is deleted if it changes during the move
+        let names = self.list_keys("refs/", None).await.context("List of refs failed")?;
+        let refs: Vec<(String, RemoteRef)> = parallel_map(names, self.parallelism, |name| async move {
+            Ok(self.read_remote_ref(&name).await?.map(|found| (name, found)))
+        }).await?.into_iter().flatten().collect();
+        let manifest = Manifest {
+            refs: refs.iter().map(|(name, found)| (name.clone(), found.sha.clone())).collect(),
+            ..Manifest::default()
+        };
+        self.write_manifest(&manifest).await?;
+        // The manifest is what counts from now on, the ref objects would only go stale. One
+        // that a push moved meanwhile is kept, and its update has to be pushed again
+        let moved: Vec<String> = parallel_map(refs, self.parallelism, |(name, found)| async move {
+            match self.write_remote_ref(&name, None, Some(&found)).await {
+                Ok(()) => Ok(None),
+                Err(e) if e.downcast_ref::<Rejection>().is_some() => Ok(Some(name)),
+                Err(e) => Err(e),
+            }
+        }).await?.into_iter().flatten().collect();
+        if !moved.is_empty() {
+            return Err(Error::msg(format!(
+                "Refs changed while moving them into the manifest, which still has their old \
+                values. Push them again: {}", moved.join(", "),
+            )))
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_manifest_round_trip() {
+        let mut manifest = Manifest { version: 3, ..Manifest::default() };
+        manifest.refs.insert("refs/heads/main".to_string(), "a".repeat(40));
+        manifest.refs.insert("refs/tags/v1.0".to_string(), "b".repeat(40));
+        let data = manifest.serialize();
+        assert_eq!(data, format!("version 3\n{} refs/heads/main\n{} refs/tags/v1.0\n", "a".repeat(40), "b".repeat(40)));
+        assert_eq!(Manifest::parse(&data).unwrap(), manifest);
+    }
+    #[test]
+    fn test_invalid_manifest() {
+        assert!(Manifest::parse("").is_err());
+        assert!(Manifest::parse("version x\n").is_err());
+        assert!(Manifest::parse("version 1\nrefs/heads/main\n").is_err());
+    }
+}
@@ -1,49 +1,407 @@
 use crate::cli;
 
-use super::util::{new_bucket, parse_remote_url};
+use super::codec::Codec;
+use super::creds;
+use super::config::{expand_home, ConfigFile, Settings};
+use super::crypt::Cipher;
+use super::local::common_dir;
+use super::options::Options;
+use super::util::{is_endpoint_name, new_bucket, parse_bucket_url, parse_remote_url, parse_url_query, BucketStyle};
 
-use log::{trace, debug};
-use anyhow::{Context, Result};
+use log::{trace, debug, info};
+use anyhow::{Context, Error, Result};
 
-use std::path::PathBuf;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::Mutex;
+use tokio::io::AsyncWriteExt;
 use s3::bucket::Bucket;
+use chrono::{DateTime, Utc};
+use git_hash::ObjectId;
 use git_odb::compound::Db;
 
+/// Where the bucket of a remote is, and who to be when talking to it
+#[derive(Debug, PartialEq)]
+struct Location<'a> {
+    profile: Option<String>,
+    endpoint: Option<String>,
+    region: Option<String>,
+    bucket: &'a str,
+    style: BucketStyle,
+    key_prefix: Option<&'a str>,
+}
+
+/// Find the bucket of remote `remote` at `remote_url`, and the settings it runs with: `git`
+/// config, the config `file`, then `env`. The URL wins over all of them, but `s3://<bucket>`
+/// leaves the endpoint to the settings when a bucket is set up in the file or an endpoint is set
+/// anywhere, and a path style URL only defaults the style
+fn locate<'a>(
+    remote_url: &'a str, remote: &str, git: Settings, file: &ConfigFile, env: Settings,
+) -> Result<(Location<'a>, Settings)> {
+    let (remote_url, url_region, query_prefix) = parse_url_query(remote_url)?;
+    let names_bucket = |name: &str| file.buckets.contains_key(name) || (
+        !is_endpoint_name(name)
+            && (git.endpoint.is_some() || file.settings(remote, name).endpoint.is_some())
+    );
+    let (url_profile, url_endpoint, bucket, url_style, path_prefix) =
+        match parse_bucket_url(remote_url, names_bucket) {
+            Some((profile, bucket, prefix)) => (profile, None, bucket, None, prefix),
+            None => {
+                let (profile, endpoint, bucket, style, prefix) = parse_remote_url(remote_url)
+                    .context("Unable to parse remote URL")?;
+                (profile, Some(endpoint), bucket, style, prefix)
+            },
+        };
+    let key_prefix = match (path_prefix, query_prefix) {
+        (Some(_), Some(_)) => return Err(Error::msg("Key prefix given both after the bucket and as ?prefix=")),
+        (path, query) => path.or(query),
+    };
+    let settings = Settings::layered(git, file.settings(remote, bucket), env);
+    let location = Location {
+        profile: url_profile.map(String::from).or_else(|| settings.profile.clone()),
+        endpoint: url_endpoint.map(String::from).or_else(|| settings.endpoint.clone()),
+        region: url_region.map(String::from).or_else(|| settings.region.clone()),
+        bucket,
+        style: url_style.or(settings.style).unwrap_or(BucketStyle::Path),
+        key_prefix,
+    };
+    Ok((location, settings))
+}
+
 /// Struct containing data needed for methods
 pub struct Remote {
     /// Path to local git object store we're reading from
     pub git_dir: PathBuf,
-    /// Bucket we're communicating with
-    pub bucket: Bucket,
+    /// Path to the objects and refs shared by every worktree. Same as `git_dir` outside of
+    /// linked worktrees
+    pub common_dir: PathBuf,
+    /// Bucket we're communicating with, and when its credentials expire. Requests go through
+    /// `bucket()`, which renews them first
+    bucket: Mutex<(Bucket, Option<DateTime<Utc>>)>,
+    /// Held while credentials are renewed
+    renewing: futures::lock::Mutex<()>,
+    /// Profile the bucket's credentials come from, if one is set
+    profile: Option<String>,
+    /// Storage class packs and their indexes are written with, the bucket's default if unset
+    pub storage_class: Option<String>,
+    /// Prepended to every key of the repository, so a bucket can hold several. Either empty or
+    /// ending with `/`
+    pub prefix: String,
     /// Git database we're saving data to
     pub git_db: Db,
+    /// Options set by git for this session
+    pub options: Options,
+    /// Most requests to the bucket in flight at once
+    pub parallelism: usize,
+    /// Codec packs are compressed with when uploaded
+    pub compression: Codec,
+    /// Encrypts everything stored in the bucket, if an encryption key is set
+    pub cipher: Option<Cipher>,
+    /// Refuse to update refs if the bucket ignores conditional writes, rather than warn
+    pub require_conditional_writes: bool,
 }
 
+/// Requests in flight at once when parallelism isn't set
+const DEFAULT_PARALLELISM: usize = 8;
+
 impl Remote {
     /// Create a new Remote object. Mostly just contains the s3::bucket::Bucket and git object
     /// store database, and helper methods to access them
     ///
-    /// Reads in options passed by structopts cli input
+    /// Reads in options passed by structopts cli input. Settings come from the remote URL first,
+    /// then git config, the config file, and the environment
     pub fn new(opts: cli::Opts) -> Result<Self> {
         debug!("Creating new remote with opts: {:?}", opts);
 
         // Build top level path
         let git_dir = PathBuf::from(opts.git_dir);
         debug!("GIT_DIR is \"{:?}\"", git_dir);
+        debug!("Remote name is \"{}\"", opts.remote_name);
 
         // Build object DB
-        let mut obj_dir = git_dir.clone(); obj_dir.push("objects");
+        let common_dir = common_dir(&git_dir)?;
+        debug!("Common dir is \"{:?}\"", common_dir);
+        let mut obj_dir = common_dir.clone(); obj_dir.push("objects");
         let db = Db::at(obj_dir)
             .context("Unable to create git db")?;
 
+        // Find the bucket, and the settings it and the URL leave to the rest
+        let settings = Settings::from_git_config(&common_dir, &opts.remote_name)?;
+        let config_file = ConfigFile::load(&opts.config)?;
+        let (location, settings) = locate(
+            &opts.remote_url, &opts.remote_name, settings, &config_file, Settings::from_env(),
+        )?;
+        debug!("Location is {:?}", location);
+        debug!("Settings are {:?}", Settings { encryption_key: None, ..settings.clone() });
+
         // Build bucket
-        let (profile_name, endpoint_url, bucket_name, bucket_style) =
-            parse_remote_url(&opts.remote_url)
-            .context("Unable to parse remote URL")?;
-       let bucket = new_bucket(
-            &bucket_name, profile_name, &endpoint_url, bucket_style
+        let (bucket, expiration) = new_bucket(
+            location.bucket,
+            location.profile.as_deref(),
+            location.endpoint.as_deref(),
+            location.region.as_deref(),
+            location.style,
         )?;
         trace!("Bucket is {:?}", bucket);
-        Ok( Remote { git_dir, bucket, git_db: db})
+        let profile = location.profile;
+        let prefix = location.key_prefix.map(|p| format!("{}/", p)).unwrap_or_default();
+
+        let parallelism = match settings.parallelism {
+            Some(0) => return Err(Error::msg("Invalid parallelism 0, expected a positive number")),
+            Some(n) => n,
+            None => DEFAULT_PARALLELISM,
+        };
+        debug!("Parallelism is {}", parallelism);
+        let compression = match &settings.compression {
+            Some(value) => value.parse().context("Invalid compression")?,
+            None => Codec::None,
+        };
+        debug!("Compression is {:?}", compression);
+        let cipher = match (&settings.encryption_key, &settings.encryption_key_file) {
+            (Some(key), _) => Some(Cipher::from_hex(key).context("Invalid encryption key")?),
+            (None, Some(path)) => {
+                let path = expand_home(path);
+                let key = fs::read_to_string(&path)
+                    .with_context(|| format!("Unable to read encryption key file {:?}", path))?;
+                Some(Cipher::from_hex(&key)
+                    .with_context(|| format!("Invalid key in encryption key file {:?}", path))?)
+            },
+            (None, None) => None,
+        };
+        debug!("Encryption is {}", if cipher.is_some() { "on" } else { "off" });
+        let storage_class = settings.storage_class;
+        let require_conditional_writes = settings.require_conditional_writes.unwrap_or(false);
+        Ok( Remote {
+            git_dir, common_dir, bucket: Mutex::new((bucket, expiration)), renewing: Default::default(),
+            profile, storage_class, prefix, git_db: db,
+            options: Options::default(), parallelism, compression, cipher, require_conditional_writes,
+        })
+    }
+
+    /// Key `name` is stored under in the bucket, below the repository's prefix. Everything but
+    /// the raw bucket calls works with names
+    pub fn key(&self, name: &str) -> String {
+        format!("{}{}", self.prefix, name)
+    }
+    /// List the names of everything in the repository starting with `prefix`. If `delimiter` is
+    /// set, names containing it after the prefix are rolled up and not returned
+    pub async fn list_keys(&self, prefix: &str, delimiter: Option<&str>) -> Result<Vec<String>> {
+        let results = self.bucket().await.list(self.key(prefix), delimiter.map(String::from)).await
+            .with_context(|| format!("List of \'{}\' failed", self.key(prefix)))?;
+        let mut keys = Vec::new();
+        for r in results {
+            trace!("Result in list is {:?}", r);
+            keys.extend(r.contents.into_iter()
+                .filter_map(|object| object.key.strip_prefix(&self.prefix).map(String::from)));
+        }
+        Ok(keys)
+    }
+    /// Path of a temporary file named `name` for this process, to hold transfers on their way to
+    /// or from the bucket
+    pub fn temp_path(&self, name: &str) -> Result<PathBuf> {
+        let dir = self.common_dir.join("s3").join("tmp");
+        fs::create_dir_all(&dir)
+            .with_context(|| format!("Unable to create temporary directory {:?}", dir))?;
+        Ok(dir.join(format!("{}-{}", process::id(), name)))
+    }
+    /// Stream object `name` into the file at `path`, without holding it in memory
+    pub async fn download_file(&self, name: &str, path: &Path) -> Result<()> {
+        let key = self.key(name);
+        let mut file = tokio::fs::File::create(path).await
+            .with_context(|| format!("Unable to create {:?}", path))?;
+        let code = self.bucket().await.tokio_get_object_stream(&key, &mut file).await
+            .with_context(|| format!("Unable to fetch \'{}\'", key))?;
+        file.flush().await
+            .with_context(|| format!("Unable to write {:?}", path))?;
+        debug!("Fetch for \'{}\': {}", key, code);
+        if code != 200 {
+            // What was written is the error response
+            fs::remove_file(path).ok();
+            return Err(Error::msg(format!("Non-okay fetch for \'{}\': {}", key, code)))
+        }
+        Ok(())
+    }
+    /// Bucket to make requests with. Temporary credentials are loaded again shortly before they
+    /// expire, so long pushes and fetches outlast them. That blocks, so it runs on its own
+    /// thread while transfers already under way carry on
+    pub async fn bucket(&self) -> Bucket {
+        let (bucket, expiration) = self.current_bucket();
+        if !creds::expires_soon(expiration) {
+            return bucket
+        }
+        // Only one request loads them, the rest wait for it rather than load them again
+        let _renewing = self.renewing.lock().await;
+        let (bucket, expiration) = self.current_bucket();
+        if !creds::expires_soon(expiration) {
+            return bucket
+        }
+        debug!("Credentials expire at {:?}, loading them again", expiration);
+        let profile = self.profile.clone();
+        let loaded = tokio::task::spawn_blocking(move || creds::load(profile.as_deref())).await
+            .map_err(|e| Error::msg(e.to_string()))
+            .and_then(|loaded| loaded);
+        match loaded {
+            Ok(loaded) => {
+                let mut current = self.bucket.lock().expect("bucket lock is never poisoned");
+                current.0.credentials = loaded.credentials;
+                current.1 = loaded.expiration;
+                current.0.clone()
+            },
+            // Carry on with the old ones, they may still have a few minutes left
+            Err(e) => {
+                info!("Unable to renew credentials: {:?}", e);
+                bucket
+            },
+        }
+    }
+    /// Bucket with the credentials loaded last, and when they expire
+    fn current_bucket(&self) -> (Bucket, Option<DateTime<Utc>>) {
+        self.bucket.lock().expect("bucket lock is never poisoned").clone()
+    }
+    /// Bucket to write packs and their indexes with, giving them the configured storage class
+    pub async fn pack_bucket(&self) -> Bucket {
+        let mut bucket = self.bucket().await;
+        if let Some(class) = &self.storage_class {
+            bucket.add_header("x-amz-storage-class", class);
+        }
+        bucket
+    }
+    /// Check if an object exists in the local object database without decoding it
+    pub fn has_object(&self, id: &ObjectId) -> bool {
+        self.git_db.loose.contains(id)
+            || self.git_db.packs.iter().any(|p| p.index.lookup(id).is_some())
+    }
+    /// Report progress to the user on stderr, if git asked for it
+    pub fn progress(&self, msg: &str) {
+        if self.options.progress && self.options.verbosity > 0 {
+            eprintln!("{}", msg);
+        }
+    }
+}
+
+/// A scratch repository made with the git CLI, and removed again when dropped
+#[cfg(test)]
+pub struct TestRepo {
+    pub dir: PathBuf,
+}
+
+#[cfg(test)]
+impl TestRepo {
+    /// Initialise a repository in a directory of the temp dir named after `name`
+    pub fn new(name: &str) -> TestRepo {
+        let dir = std::env::temp_dir().join(format!("git-remote-s3-{}-{}", name, std::process::id()));
+        std::fs::remove_dir_all(&dir).ok();
+        std::fs::create_dir_all(&dir).unwrap();
+        let repo = TestRepo { dir };
+        repo.git(&["init", "--quiet", "--initial-branch=main"]);
+        repo
+    }
+    /// Run git in the repository, returning what it printed
+    pub fn git(&self, args: &[&str]) -> String {
+        let output = std::process::Command::new("git")
+            .arg("-C").arg(&self.dir)
+            .args(["-c", "user.name=test", "-c", "user.email=test@example.com", "-c", "commit.gpgsign=false"])
+            .args(args)
+            .output().unwrap();
+        assert!(output.status.success(), "git {:?}: {}", args, String::from_utf8_lossy(&output.stderr));
+        String::from_utf8(output.stdout).unwrap().trim().to_string()
+    }
+    /// Write `content` to `file` and commit it, returning the new commit
+    pub fn commit(&self, file: &str, content: &str) -> ObjectId {
+        std::fs::write(self.dir.join(file), content).unwrap();
+        self.git(&["add", file]);
+        self.git(&["commit", "--quiet", "-m", file]);
+        self.id("HEAD")
+    }
+    /// Object `rev` names
+    pub fn id(&self, rev: &str) -> ObjectId {
+        ObjectId::from_hex(self.git(&["rev-parse", rev]).as_bytes()).unwrap()
+    }
+    /// Remote reading from the repository, with a bucket it never reaches
+    pub fn remote(&self) -> Remote {
+        self.remote_at("http://127.0.0.1:1")
+    }
+    /// Remote reading from the repository, with the path style bucket `test` at `endpoint`
+    pub fn remote_at(&self, endpoint: &str) -> Remote {
+        let git_dir = self.dir.join(".git");
+        let credentials = s3::creds::Credentials {
+            access_key: Some("test".to_string()),
+            secret_key: Some("test".to_string()),
+            security_token: None,
+            session_token: None,
+        };
+        let region = s3::Region::Custom { region: "us-east-1".to_string(), endpoint: endpoint.to_string() };
+        let bucket = Bucket::new_with_path_style("test", region, credentials).unwrap();
+        Remote {
+            git_dir: git_dir.clone(), common_dir: git_dir.clone(),
+            bucket: Mutex::new((bucket, None)), renewing: Default::default(),
+            profile: None, storage_class: None, prefix: String::new(),
+            git_db: Db::at(git_dir.join("objects")).unwrap(), options: Options::default(),
+            parallelism: 8, compression: Codec::None, cipher: None, require_conditional_writes: false,
+        }
+    }
+}
+
+#[cfg(test)]
+impl Drop for TestRepo {
+    fn drop(&mut self) {
+        std::fs::remove_dir_all(&self.dir).ok();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::process::Command;
+
+    #[test]
+    fn test_locate() {
+        let file = ConfigFile::parse("[buckets.shared]\nregion = \"eu-west-1\"").unwrap();
+        let none = Settings::default;
+        let (location, _) = locate("s3://shared/repo", "origin", none(), &file, none()).unwrap();
+        assert_eq!(location, Location {
+            profile: None, endpoint: None, region: Some("eu-west-1".to_string()), bucket: "shared",
+            style: BucketStyle::Path, key_prefix: Some("repo"),
+        });
+        // Without an endpoint set anywhere, the first component is one
+        assert!(locate("s3://bucket", "origin", none(), &file, none()).is_err());
+
+        let file = ConfigFile::parse("[remotes.origin]\nendpoint = \"ceph.example.com\"\nstyle = \"virtual-host\"").unwrap();
+        let (location, _) = locate("s3://bucket", "origin", none(), &file, none()).unwrap();
+        assert_eq!(location.endpoint.as_deref(), Some("ceph.example.com"));
+        assert_eq!(location.style, BucketStyle::Subdomain);
+        // Endpoints in the URL still win
+        let (location, _) = locate("s3://localhost:9000/bucket", "origin", none(), &file, none()).unwrap();
+        assert_eq!(location.endpoint.as_deref(), Some("localhost:9000"));
+        assert_eq!(location.style, BucketStyle::Subdomain);
+        let (location, _) = locate("s3://eu-west-2/bucket", "origin", none(), &file, none()).unwrap();
+        assert_eq!(location.endpoint.as_deref(), Some("eu-west-2"));
+    }
+    #[test]
+    fn test_locate_from_git_config() {
+        let dir = std::env::temp_dir().join(format!("git-remote-s3-remote-{}", std::process::id()));
+        let git = |args: &[&str]| assert!(Command::new("git").arg("-C").arg(&dir).args(args)
+            .status().unwrap().success());
+        fs::create_dir_all(&dir).unwrap();
+        git(&["init", "--quiet"]);
+        git(&["config", "s3.endpoint", "http://localhost:9000"]);
+        git(&["config", "remote.origin.s3pathStyle", "false"]);
+        let settings = || Settings::from_git_config(&dir.join(".git"), "origin").unwrap();
+        let file = ConfigFile::default();
+
+        let (location, _) = locate("s3://bucket/repo", "origin", settings(), &file, Settings::default()).unwrap();
+        assert_eq!(location.endpoint.as_deref(), Some("http://localhost:9000"));
+        assert_eq!(location.bucket, "bucket");
+        assert_eq!(location.key_prefix, Some("repo"));
+        assert_eq!(location.style, BucketStyle::Subdomain);
+        let (location, _) = locate("s3://http://localhost:9001/bucket", "origin", settings(), &file, Settings::default()).unwrap();
+        assert_eq!(location.endpoint.as_deref(), Some("http://localhost:9001"));
+        assert_eq!(location.style, BucketStyle::Subdomain);
+
+        git(&["config", "remote.origin.s3pathStyle", "true"]);
+        let (location, _) = locate("s3://bucket", "origin", settings(), &file, Settings::default()).unwrap();
+        assert_eq!(location.style, BucketStyle::Path);
+        fs::remove_dir_all(&dir).unwrap();
     }
 }
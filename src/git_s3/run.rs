@@ -1,13 +1,16 @@
+use super::push::{self, PushSpec};
 use super::remote::Remote;
+use super::util::parallel_map;
 
 use anyhow::{Context, Result, Error};
 use log::{info, trace, debug, error};
 use std::io;
 
 impl Remote {
-    /// List commands supported by this helper. Currently fetch and push.
+    /// List commands supported by this helper. Currently option, fetch and push.
     pub fn capabilities(&self) -> Result<()> {
-        // println!("option");
+        println!("option");
+        println!("check-connectivity");
         println!("fetch");
         println!("push");
         Ok(())
@@ -33,34 +36,46 @@ impl Remote {
      *
      * Needed by push
      */
-    /// List refs that this bucket knows about. Returns all objects in s3 prefaced with `refs/`.
-    /// Takes a parameter to return default remote branch (`HEAD`)
+    /// List refs that this bucket knows about. Returns all objects in s3 prefaced with `refs/`,
+    /// and the remote `HEAD` as a symref unless listing for a push
     /// Prints "<data> <key>"
-    pub fn list(&self, _include_head: bool) -> Result<()> {
-        self.list_prefix("refs/").context("List refs")
-    }
-    fn list_prefix(&self, search_prefix: &str) -> Result<()> {
-        let results = self.bucket.list_blocking(search_prefix.to_string(), None)
-            .context("List command failed")?;
-        for (r, code) in results {
-            if code != 200 {
-                return Err(Error::msg(format!("Non-okay list for \'{}\': {}", search_prefix, code)))
-            }
-            trace!("Result in list is {:?}", r);
-            for object in r.contents {
-                trace!("Content in list is {:?}", object);
-                let (data, code) = self.bucket.get_object_blocking(&object.key)
-                    .with_context(|| format!("Unable to list content for \'{}\'", &object.key))?;
-                if code != 200 {
-                    return Err(Error::msg(format!("Non-okay cat for \'{}\': {}", &object.key, code)))
-                }
-                let string_data = std::str::from_utf8(&data)?;
-                info!("List output is: {} {}", string_data.trim(), object.key);
-                println!("{} {}", string_data.trim(), object.key);
+    pub async fn list(&self, for_push: bool) -> Result<()> {
+        let refs = self.remote_refs().await.context("List refs")?;
+        for (name, value) in refs.iter() {
+            info!("List output is: {} {}", value, name);
+            println!("{} {}", value, name);
+        }
+        if !for_push {
+            // A HEAD pointing at a missing branch would only confuse git
+            match self.remote_head().await.context("List HEAD")? {
+                Some(head) if refs.iter().any(|(name, _)| name == &head) => {
+                    info!("List output is: @{} HEAD", head);
+                    println!("@{} HEAD", head);
+                },
+                head => debug!("Not listing HEAD: {:?}", head),
             }
         }
         Ok(())
     }
+    /// Read every ref saved in the bucket, returning pairs of "<key> <data>". Refs come from the
+    /// manifest if the bucket has one
+    pub async fn remote_refs(&self) -> Result<Vec<(String, String)>> {
+        if let Some(manifest) = self.read_manifest().await? {
+            return Ok(manifest.refs.into_iter().collect())
+        }
+        let keys = self.list_keys("refs/", None).await.context("List command failed")?;
+        parallel_map(keys, self.parallelism, |key| async move {
+            trace!("Content in list is {:?}", key);
+            let (data, code) = self.bucket().await.get_object(self.key(&key)).await
+                .with_context(|| format!("Unable to list content for \'{}\'", &key))?;
+            if code != 200 {
+                return Err(Error::msg(format!("Non-okay cat for \'{}\': {}", &key, code)))
+            }
+            let data = self.unseal(&key, data)?;
+            let string_data = std::str::from_utf8(&data)?.trim().to_string();
+            Ok((key, string_data))
+        }).await
+    }
     /*
      * option <name> <value>
      *
@@ -71,20 +86,24 @@ impl Remote {
      *
      * Needed by option.
      */
-    /*
-     pub fn option(&self) -> Result<()> {
-        Ok(())
+    /// Set an option, printing "ok", "unsupported" or "error <msg>"
+    pub fn option(&mut self, name: &str, value: &str) {
+        match self.options.set(name, value) {
+            Ok(true) => println!("ok"),
+            Ok(false) => println!("unsupported"),
+            Err(e) => println!("error {}", e),
+        }
     }
-    */
-    pub fn run(&self) -> Result<()> {
+    pub async fn run(&mut self) -> Result<()> {
         loop {
             debug!("Reading new line from stdin");
-            let mut buf = String::new();
+            let buf = read_line()?;
 
-            // Read next line from stdin
-            io::stdin().read_line(&mut buf)
-                .context("Could not read line from stdin")?;
-            debug!("Line is: {:?}", &buf);
+            // A blank line outside of a batch (or EOF) ends the session
+            if buf.is_empty() {
+                debug!("Exiting");
+                break Ok(())
+            }
 
             // Split it by space, trim whitespace
             let mut line_vec = buf
@@ -106,62 +125,57 @@ impl Remote {
                         None => false,
                     };
                     if for_push {debug!("For-push")};
-                    self.list(for_push)
+                    self.list(for_push).await
                 },
-                /*
                 "option" => {
-                    debug!("Starting option");
-                    self.option()
+                    info!("Running option");
+                    let name = line_vec.next()
+                        .ok_or_else(|| Error::msg(format!("Option command has invalid arg for name: {}", buf)))?;
+                    let value = line_vec.collect::<Vec<_>>().join(" ");
+                    debug!("Setting option {} to {}", name, value);
+                    // Replies with a single line, no blank line to end it
+                    self.option(name, &value);
+                    continue
                 },
-                */
                 "fetch" => {
                     info!("Running fetch");
                     // Parse for fetch
                     let fetch_err = "Fetch command has invalid arg";
-                    let sha = line_vec.next()
-                        .ok_or_else(|| Error::msg(format!("{} for sha: {}", fetch_err, buf)))?;
-                    trace!("Fetch sha is: {}", sha);
-                    let name = line_vec.next()
-                        .ok_or_else(|| Error::msg(format!("{} for name: {}", fetch_err, buf)))?;
-                    trace!("Fetch name is: {}", name);
-                    self.fetch(sha)
+                    let batch = read_batch(&buf)?;
+                    let mut sha1s = Vec::new();
+                    for line in batch.iter() {
+                        let mut args = line.split(' ').skip(1);
+                        let sha = args.next()
+                            .ok_or_else(|| Error::msg(format!("{} for sha: {}", fetch_err, line)))?;
+                        trace!("Fetch sha is: {}", sha);
+                        let name = args.next()
+                            .ok_or_else(|| Error::msg(format!("{} for name: {}", fetch_err, line)))?;
+                        trace!("Fetch name is: {}", name);
+                        sha1s.push(sha);
+                    }
+                    // Tells git it can skip its own connectivity walk
+                    self.fetch(&sha1s).await.map(|checked| if checked {
+                        println!("connectivity-ok");
+                    })
                 },
                 "push" => {
                     info!("Running push");
-
                     // Parse for push
                     let push_err = "Push command has invalid arg";
-                    let mut colon_iter = line_vec.next()
-                        .ok_or_else(|| Error::msg(format!("{} from colon split: {}", push_err, buf)))?
-                        .split(':');
-                    // Get src w/ unknown force prefix
-                    let src_str_unk = colon_iter.next()
-                        .ok_or_else(|| Error::msg(format!("{} from src parsing: {}", push_err, buf)))?;
-                    // Key off force push
-                    let force_push = match src_str_unk.chars().next() {
-                        Some('+') => true,
-                        Some(_) => false,
-                        _ => return Err(Error::msg(format!("{} from force-push parsing: {}", push_err, buf))),
-                    };
-                    // Remove src prefix if it exists
-                    let src_str = match src_str_unk.strip_prefix('+') {
-                        Some(s) => s,
-                        None => src_str_unk
-                    };
-                    // Get regular dst
-                    let dst_str = match colon_iter.next() {
-                        Some(s) => s,
-                        _ => return Err(Error::msg(format!("{} from dst parsing: {}", push_err, buf))),
-                    };
-
-                    debug!("Pushing {} to {} {}", src_str, dst_str, if force_push {"forcefully"} else {""});
-                    self.push(src_str, dst_str, force_push)
+                    let batch = read_batch(&buf)?;
+                    let specs = batch.iter()
+                        .map(|line| match line.split(' ').nth(1) {
+                            Some(arg) => PushSpec::parse(arg),
+                            None => Err(Error::msg(format!("{}: {}", push_err, line))),
+                        })
+                        .collect::<Result<Vec<_>>>()?;
+                    // One status line per ref, the blank line below ends the batch
+                    for (dst, result) in self.push(&specs).await {
+                        println!("{}", push::status_line(&dst, &result));
+                    }
+                    Ok(())
                 },
-                _ => {
-                    debug!("No matching command found for: {}", command);
-                    debug!("Exiting");
-                    break Ok(())
-                }
+                _ => Err(Error::msg(format!("No matching command found for: {}", command))),
             };
             match result {
                 Ok(()) => {
@@ -177,3 +191,31 @@ impl Remote {
         }
     }
 }
+
+/// Read a line from stdin, without the trailing newline. Returns an empty string at EOF. Blocks
+/// the runtime, which is fine as nothing else runs between commands
+fn read_line() -> Result<String> {
+    let mut buf = String::new();
+    io::stdin().read_line(&mut buf)
+        .context("Could not read line from stdin")?;
+    debug!("Line is: {:?}", &buf);
+    Ok(buf.trim_end().to_string())
+}
+
+/// Read the rest of a batch that started with `first`, up to the blank line ending it. Every
+/// line of a batch must be the same command
+fn read_batch(first: &str) -> Result<Vec<String>> {
+    let command = first.split(' ').next().unwrap_or_default();
+    let mut batch = vec![first.to_string()];
+    loop {
+        let line = read_line()?;
+        if line.is_empty() {
+            debug!("Read batch of {} {} commands", batch.len(), command);
+            break Ok(batch)
+        }
+        if line.split(' ').next() != Some(command) {
+            break Err(Error::msg(format!("Expected {} in batch, got: {}", command, line)))
+        }
+        batch.push(line);
+    }
+}
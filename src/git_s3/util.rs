@@ -1,58 +1,209 @@
+use super::creds;
+
 use s3::bucket::Bucket;
 
 use log::{trace, debug};
 use anyhow::{Context, Error, Result};
 
-use s3::creds::Credentials;
+use s3::Region;
+use chrono::{DateTime, Utc};
+use serde::Deserialize;
+use git_hash::ObjectId;
+use git_object::Kind;
+use git_object::tree::EntryMode;
+use git_object::immutable::{Commit, Tag, Tree};
+use git_features::hash::Sha1;
+use futures::future::Future;
+use futures::stream::{self, StreamExt, TryStreamExt};
+use std::io::{self, Read, Seek, SeekFrom};
 
-#[derive(Debug,PartialEq)]
+#[derive(Debug,PartialEq,Clone,Copy,Deserialize)]
+#[serde(rename_all = "kebab-case")]
 pub enum BucketStyle {
     Path,
+    #[serde(rename = "virtual-host", alias = "subdomain")]
     Subdomain,
 }
 
 /// Instantiate new connection
 /// Params:
 /// * Name of bucket
-/// * Name of S3 profile to use. Reads from default creds file or environment
-/// * Endpoint URL
+/// * Name of AWS profile to take credentials from. Without one, see `creds::load` for where
+///   they are looked for
+/// * Endpoint URL, or AWS region name
+/// * Region to sign requests for
 /// * Bucket style to use (true for <remote>/<bucket>, false for <bucket>.<remote>
+///
+/// Returns the bucket along with when its credentials expire
 pub fn new_bucket(
-    bucket_name: &str, profile: Option<&str>, region: &str, bucket_style: BucketStyle
-) -> Result<Bucket, anyhow::Error>{
+    bucket_name: &str, profile: Option<&str>, endpoint: Option<&str>, region: Option<&str>,
+    bucket_style: BucketStyle
+) -> Result<(Bucket, Option<DateTime<Utc>>), anyhow::Error>{
 
     debug!("Building new bucket");
-    // Parse config
-    // git config --get s3.bucket ? How do remotes?
-    // Just using s3 profiles for now
-    // TODO - parse profile from remote URL (<profile>@<region>?)
-
-    let r = region.parse()
-            .with_context(|| format!("Could not create region for \"{}\"", region))?;
+    let r = bucket_region(endpoint, region)?;
     debug!("Loaded region is {}", r);
-    let c =  Credentials::new(None, None, None, None, profile)
-            .with_context(|| format!(
-                "Could not load S3 credentials for profile \"{}\"",
-                profile.unwrap_or("default"),
-            ))?;
+    let c = creds::load(profile)
+            .context("Could not load S3 credentials")?;
     // Change which bucket we create from path style
-    match bucket_style {
-        BucketStyle::Path => Bucket::new_with_path_style(bucket_name, r, c),
-        BucketStyle::Subdomain => Bucket::new(bucket_name, r, c),
-    }.with_context(|| format!("Could not load S3 bucket \"{}\"", bucket_name))
+    let bucket = match bucket_style {
+        BucketStyle::Path => Bucket::new_with_path_style(bucket_name, r, c.credentials),
+        BucketStyle::Subdomain => Bucket::new(bucket_name, r, c.credentials),
+    }.with_context(|| format!("Could not load S3 bucket \"{}\"", bucket_name))?;
+    Ok((bucket, c.expiration))
+}
+
+/// Region signed for when talking to a service that isn't AWS and no region is set. Most S3
+/// compatible services expect this unless configured otherwise
+const DEFAULT_REGION: &str = "us-east-1";
+
+/// Region to connect to and sign requests for. The endpoint is either an AWS region name or the
+/// host or URL of a service. AWS regions and hosts are signed for the region they name, any other
+/// service for `region`, falling back to `DEFAULT_REGION`. Without an endpoint, `region` is the
+/// AWS region to connect to
+fn bucket_region(endpoint: Option<&str>, region: Option<&str>) -> Result<Region> {
+    match (endpoint, region) {
+        (Some(endpoint), _) if is_region_name(endpoint) => aws_region(endpoint),
+        (Some(endpoint), region) => {
+            let region = match aws_host_region(endpoint) {
+                Some(host_region) => host_region,
+                None => region.unwrap_or(DEFAULT_REGION),
+            };
+            Ok(Region::Custom { region: region.to_string(), endpoint: endpoint.to_string() })
+        },
+        (None, Some(region)) if is_region_name(region) => aws_region(region),
+        (None, Some(region)) => Err(Error::msg(format!(
+            "No endpoint for the bucket, and \"{}\" is not an AWS region", region,
+        ))),
+        (None, None) => Err(Error::msg("No endpoint or region for the bucket")),
+    }
+}
+
+/// AWS region `name`, including ones newer than those rust-s3 knows about
+fn aws_region(name: &str) -> Result<Region> {
+    match name.parse::<Region>()
+        .with_context(|| format!("Could not create region for \"{}\"", name))? {
+        Region::Custom { .. } => Ok(Region::Custom {
+            region: name.to_string(),
+            endpoint: format!("s3.{}.amazonaws.com", name),
+        }),
+        known => Ok(known),
+    }
+}
+
+/// Prefixes of the AWS region names in each partition
+const REGION_PREFIXES: &[&str] = &["us", "eu", "ap", "sa", "ca", "me", "af", "il", "mx", "cn", "us-gov"];
+
+/// Whether `name` is a region name rather than a host, like `eu-west-2` or `us-gov-east-1`.
+/// Regions rust-s3 doesn't know yet have to be `<prefix>-<area>-<number>` with one of the AWS
+/// prefixes, so hosts like `ceph-rgw-01` aren't mistaken for one
+fn is_region_name(name: &str) -> bool {
+    if !matches!(name.parse::<Region>(), Ok(Region::Custom { .. })) {
+        return true
+    }
+    REGION_PREFIXES.iter().any(|prefix| {
+        name.strip_prefix(prefix)
+            .and_then(|rest| rest.strip_prefix('-'))
+            .and_then(|rest| rest.split_once('-'))
+            .is_some_and(|(area, number)| !area.is_empty()
+                && area.chars().all(|c| c.is_ascii_lowercase())
+                && !number.is_empty()
+                && number.chars().all(|c| c.is_ascii_digit()))
+    })
 }
 
-/// Parse remote_url string into optional profile name, and mandatory remote URL and bucket name.
-/// Key off `/` or `:` for path style to use
+/// Region an AWS S3 endpoint is in, from hosts like `s3.eu-west-2.amazonaws.com`,
+/// `s3-eu-west-2.amazonaws.com` or `s3.dualstack.eu-west-2.amazonaws.com`. `None` for hosts
+/// outside AWS
+fn aws_host_region(endpoint: &str) -> Option<&str> {
+    let host = endpoint.splitn(2, "://").last()?;
+    let host = host.split(&[':', '/'][..]).next()?;
+    let labels = host.strip_suffix(".amazonaws.com")
+        .or_else(|| host.strip_suffix(".amazonaws.com.cn"))?;
+    let region = labels.split('.')
+        .map(|label| label.strip_prefix("s3-").unwrap_or(label))
+        .find(|label| is_region_name(label));
+    // The global endpoint, s3.amazonaws.com
+    Some(region.unwrap_or(DEFAULT_REGION))
+}
+
+/// Whether the start of a remote URL is an endpoint rather than a bucket: an AWS region, or a
+/// host with a domain, scheme or port
+pub fn is_endpoint_name(name: &str) -> bool {
+    name.contains(&['.', ':'][..]) || is_region_name(name)
+}
+
+/// Split the query off a remote URL, returning the URL without it, and the signing region and
+/// key prefix it sets, if any
+///
+/// Ex;
+/// s3://ceph.example.com/<bucket>?region=<region>
+/// s3://ceph.example.com/<bucket>?prefix=<prefix>
+pub fn parse_url_query(remote_url: &str) -> Result<(&str, Option<&str>, Option<&str>)> {
+    let (url, query) = match remote_url.split_once('?') {
+        Some(split) => split,
+        None => return Ok((remote_url, None, None)),
+    };
+    let mut region = None;
+    let mut prefix = None;
+    for param in query.split('&').filter(|param| !param.is_empty()) {
+        match param.split_once('=') {
+            Some(("region", value)) if !value.is_empty() => region = Some(value),
+            Some(("prefix", value)) if !value.trim_matches('/').is_empty() =>
+                prefix = Some(value.trim_matches('/')),
+            _ => return Err(Error::msg(format!("Unknown parameter \"{}\" in {}", param, remote_url))),
+        }
+    }
+    debug!("Parsed region \"{}\" and prefix \"{}\" from {}",
+        region.unwrap_or(""), prefix.unwrap_or(""), remote_url);
+    Ok((url, region, prefix))
+}
+
+/// Parts of a remote URL naming a bucket without an endpoint: optional profile name, bucket name
+/// and optional key prefix
+pub type BucketUrl<'a> = (Option<&'a str>, &'a str, Option<&'a str>);
+
+/// Parse a remote URL starting with a bucket rather than an endpoint, for buckets whose endpoint
+/// comes from the settings. Returns `None` unless `is_bucket` is true for the first component
+///
+/// Ex;
+/// s3://<bucket>
+/// s3://<profile_name>@<bucket>/<prefix>
+pub fn parse_bucket_url(remote_url: &str, is_bucket: impl Fn(&str) -> bool) -> Option<BucketUrl<'_>> {
+    let rest = remote_url.strip_prefix("s3://")?;
+    let (profile, rest) = match rest.find('@') {
+        Some(i) => (Some(&rest[..i]), &rest[i + 1..]),
+        None => (None, rest),
+    };
+    let mut bucket_prefix = rest.splitn(2, '/');
+    let bucket = bucket_prefix.next().filter(|bucket| is_bucket(bucket))?;
+    let key_prefix = bucket_prefix.next()
+        .map(|prefix| prefix.trim_matches('/'))
+        .filter(|prefix| !prefix.is_empty());
+    debug!("Parsed configured bucket \"{}\" from {}", bucket, remote_url);
+    Some((profile, bucket, key_prefix))
+}
+
+/// Parts of a remote URL: optional profile name, region or endpoint, bucket name, bucket style if
+/// the URL picks one and optional key prefix
+pub type RemoteUrl<'a> = (Option<&'a str>, &'a str, &'a str, Option<BucketStyle>, Option<&'a str>);
+
+/// Parse remote_url string into optional profile name, mandatory remote URL and bucket name, and
+/// an optional key prefix. A `:` before the bucket picks subdomain style, a `/` leaves the style to
+/// the settings. Anything after the bucket is the prefix every key of the repository is stored
+/// under, so a bucket can hold several. In path style, that is only the case for AWS regions and
+/// hosts, which never have a path. Other endpoints may, so their bucket is the last component
 ///
 /// Ex;
 /// s3://<profile_name>@<region>/<bucket>
 /// s3://<region>/<bucket>
+/// s3://<region>/<bucket>/<prefix>
+/// s3://http://localhost:9000/<bucket>
 /// s3://example.com/s3/url/<bucket>
-/// s3://s3.example.com/<bucket>
 /// s3://<region>:<bucket>
-/// s3://s3.example.com:<bucket>
-pub fn parse_remote_url(remote_url: &str) -> Result<(Option<&str>, &str, &str, BucketStyle)> {
+/// s3://s3.example.com:<bucket>/<prefix>
+/// s3://example.com/s3/url:<bucket>
+pub fn parse_remote_url(remote_url: &str) -> Result<RemoteUrl<'_>> {
     debug!("Parsing remote url {}", remote_url);
 
     // Remove prefix
@@ -73,62 +224,183 @@ pub fn parse_remote_url(remote_url: &str) -> Result<(Option<&str>, &str, &str, B
         profile.unwrap_or("default"), remote_url
     );
 
-    // Find region. From the remaining url, split on / or :
-    // later, and join to the bucket name
-
     // Index changes if profile exists or not
     let start_index: usize = match profile {
         Some(_) => 1,
         None => 0,
     };
     let remaining_str = v[start_index];
-    // Split on last / or :
-    let region_bucket: Vec<&str> = remaining_str.rsplitn(2,&['/',':'][..]).collect();
-    trace!("Split region_bucket vector is {:?}", region_bucket);
-    let region = region_bucket.last()
-        .ok_or_else(|| Error::msg("Invalid region name"))?;
+
+    // An endpoint given as a URL keeps its scheme
+    let scheme_len = ["http://", "https://"].iter()
+        .find(|scheme| remaining_str.starts_with(*scheme))
+        .map_or(0, |scheme| scheme.len());
+    let after_scheme = &remaining_str[scheme_len..];
+
+    // Get path style from sep. Subdomain style splits the region from the bucket on the first
+    // `:` that doesn't start a port, path style on the first `/`
+    let colon = after_scheme.match_indices(':')
+        .map(|(i, _)| i)
+        .find(|i| !starts_with_port(&after_scheme[i + 1..]));
+    let (region_len, style) = match colon {
+        Some(i) => (i, Some(BucketStyle::Subdomain)),
+        None => {
+            let first = after_scheme.find('/')
+                .with_context(|| format!("No bucket in {}", remaining_str))?;
+            let endpoint = &remaining_str[..scheme_len + first];
+            if is_region_name(endpoint) || aws_host_region(endpoint).is_some() {
+                (first, None)
+            } else {
+                let last = after_scheme.trim_end_matches('/').rfind('/').unwrap_or(first);
+                (last, None)
+            }
+        },
+    };
+    debug!("Parsed style \"{:?}\" from {}", style, remote_url);
+
+    // Find region
+    let region = &remaining_str[..scheme_len + region_len];
+    if region_len == 0 {
+        return Err(Error::msg("Invalid region name"))
+    }
     debug!("Parsed region \"{}\" from {}", region, remote_url);
 
-    // Find bucket name (last /).
-    let bucket = region_bucket.first()
+    // Find bucket name, followed by the key prefix if there is one
+    let mut bucket_prefix = after_scheme[region_len + 1..].splitn(2, '/');
+    let bucket = bucket_prefix.next()
+        .filter(|bucket| !bucket.is_empty())
         .ok_or_else(|| Error::msg("Invalid bucket name"))?;
     debug!("Parsed bucket \"{}\" from {}", bucket, remote_url);
+    let key_prefix = bucket_prefix.next()
+        .map(|prefix| prefix.trim_matches('/'))
+        .filter(|prefix| !prefix.is_empty());
+    debug!("Parsed key prefix \"{}\" from {}", key_prefix.unwrap_or(""), remote_url);
 
-    // Get path style from sep (length of split region)
-    let sep = remaining_str.chars().nth(region.len())
-        .with_context(|| format!("Unable to get seperator from {}",remaining_str))?;
-    trace!("Sep is {}", sep);
-    let style = match sep {
-        '/' => BucketStyle::Path,
-        ':' => BucketStyle::Subdomain,
-        _ => return Err(Error::msg(
-                format!("No matching bucket style for {}", sep)
-            ))
-    };
-    debug!("Parsed style \"{:?}\" from {}", style, remote_url);
+    Ok((profile, region, bucket, style, key_prefix))
+}
+
+/// Whether `rest`, following a `:`, is a port number followed by more of the URL
+fn starts_with_port(rest: &str) -> bool {
+    match rest.find(&['/', ':'][..]) {
+        Some(end) => end > 0 && rest[..end].bytes().all(|b| b.is_ascii_digit()),
+        None => false,
+    }
+}
+
+/// Parse the objects directly referenced by a git object, along with the kind each is expected to
+/// be. Commits reference their tree and parents, trees their entries, tags their target.
+/// Submodule entries (gitlinks) are left out, those commits live in another repository
+pub fn object_references(kind: Kind, data: &[u8]) -> Result<Vec<(ObjectId, Kind)>> {
+    Ok(match kind {
+        Kind::Commit => {
+            let commit_obj = Commit::from_bytes(data).context("Unable to parse commit")?;
+            std::iter::once((commit_obj.tree(), Kind::Tree))
+                .chain(commit_obj.parents().map(|p| (p, Kind::Commit)))
+                .collect()
+        },
+        Kind::Tree => {
+            let tree_obj = Tree::from_bytes(data).context("Unable to parse tree")?;
+            tree_obj.entries.iter()
+                .filter(|e| e.mode != EntryMode::Commit)
+                .map(|e| (e.oid.to_owned(), if e.mode.is_tree() { Kind::Tree } else { Kind::Blob }))
+                .collect()
+        },
+        Kind::Tag => {
+            let tag_obj = Tag::from_bytes(data).context("Unable to parse tag")?;
+            vec![(tag_obj.target(), tag_obj.target_kind)]
+        },
+        Kind::Blob => Vec::new(),
+    })
+}
 
-    Ok((profile, region, bucket, style))
+/// Ids of an object of each of `kinds`, holding the `size` bytes of `stored` from `offset` as
+/// data: the hash of its `<kind> <size>\0` header and data. Hashes every kind in a single pass
+/// through `stored`
+fn stored_object_ids<R: Read + Seek>(kinds: &[Kind], stored: &mut R, offset: u64, size: u64) -> io::Result<Vec<ObjectId>> {
+    let mut hashers: Vec<Sha1> = kinds.iter()
+        .map(|kind| {
+            let mut hasher = Sha1::default();
+            hasher.update(kind.to_bytes());
+            hasher.update(format!(" {}\0", size).as_bytes());
+            hasher
+        })
+        .collect();
+    stored.seek(SeekFrom::Start(offset))?;
+    let mut data = stored.take(size);
+    let mut buf = vec![0; 64 * 1024];
+    loop {
+        let read = data.read(&mut buf)?;
+        if read == 0 {
+            break
+        }
+        hashers.iter_mut().for_each(|hasher| hasher.update(&buf[..read]));
+    }
+    Ok(hashers.into_iter().map(|hasher| ObjectId::from_20_bytes(&hasher.digest())).collect())
+}
+
+/// Find the kind of a loose object stored in the bucket under `id`, returning it along with the
+/// offset and size of the object data in `stored`. Objects stored with their `<kind> <size>\0`
+/// header are read from it. Older versions stored bare data, typed by hashing it as each kind
+/// until one matches `id`. Reads through `stored` rather than loading it into memory
+pub fn decode_loose_object<R: Read + Seek>(id: &ObjectId, stored: &mut R) -> Result<(Kind, u64, u64)> {
+    let len = stored.seek(SeekFrom::End(0))?;
+    let mut start = Vec::new();
+    stored.seek(SeekFrom::Start(0))?;
+    stored.take(32).read_to_end(&mut start)?;
+    let with_header = start.iter().position(|b| *b == 0)
+        .and_then(|end| {
+            let header = std::str::from_utf8(&start[..end]).ok()?;
+            let mut parts = header.splitn(2, ' ');
+            let kind = Kind::from_bytes(parts.next()?.as_bytes()).ok()?;
+            let size: u64 = parts.next()?.parse().ok()?;
+            Some((kind, end as u64 + 1, size)).filter(|(_, offset, size)| offset + size == len)
+        });
+    if let Some((kind, offset, size)) = with_header {
+        if stored_object_ids(&[kind], stored, offset, size)?[0] == *id {
+            return Ok((kind, offset, size))
+        }
+    }
+    let kinds = [Kind::Commit, Kind::Tree, Kind::Blob, Kind::Tag];
+    stored_object_ids(&kinds, stored, 0, len)?.iter()
+        .position(|bare_id| bare_id == id)
+        .map(|i| (kinds[i], 0, len))
+        .ok_or_else(|| Error::msg(format!("Stored object {} doesn't match its id", id)))
+}
+
+/// Run `f` over every item with at most `limit` running at once, returning the results in the
+/// order of `items`. The first error in order is returned, and everything still running is
+/// cancelled
+pub async fn parallel_map<T, R, F, Fut>(items: Vec<T>, limit: usize, f: F) -> Result<Vec<R>>
+where F: FnMut(T) -> Fut, Fut: Future<Output = Result<R>> {
+    trace!("Running {} items, {} at once", items.len(), limit);
+    stream::iter(items)
+        .map(f)
+        .buffered(limit.max(1))
+        .try_collect()
+        .await
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use futures::executor::block_on;
 
 // s3://<profile_name>@<region>/<bucket>
 // s3://<region>/<bucket>
-// s3://example.com/s3/url/<bucket>
+// s3://<region>/<bucket>/<prefix>
 // s3://s3.example.com/<bucket>
 // s3://<region>:<bucket>
 // s3://s3.example.com:<bucket>
+// s3://example.com/s3/url:<bucket>
     #[test]
     fn test_parse_remote_url() {
         assert_eq!(parse_remote_url("s3://profile@region/bucket").unwrap(),
-        (Some("profile"),"region","bucket",BucketStyle::Path))
+        (Some("profile"),"region","bucket",None,None))
     }
     #[test]
     fn test_no_profile_parse_remote_url() {
         assert_eq!(parse_remote_url("s3://region/bucket").unwrap(),
-        (None,"region","bucket",BucketStyle::Path))
+        (None,"region","bucket",None,None))
     }
     #[test]
     #[should_panic]
@@ -138,16 +410,185 @@ mod tests {
     #[test]
     fn test_path_with_port_no_profile_parse_remote_url() {
         assert_eq!(parse_remote_url("s3://localhost:9000/bucket12345").unwrap(),
-        (None, "localhost:9000","bucket12345",BucketStyle::Path))
+        (None, "localhost:9000","bucket12345",None,None))
     }
     #[test]
     fn test_url_subdomain_no_profile_parse_remote_url() {
         assert_eq!(parse_remote_url("s3://example.com/long/url:bucket12345").unwrap(),
-        (None, "example.com/long/url","bucket12345",BucketStyle::Subdomain))
+        (None, "example.com/long/url","bucket12345",Some(BucketStyle::Subdomain),None))
     }
     #[test]
     fn test_url_port_subdomain_parse_remote_url() {
         assert_eq!(parse_remote_url("s3://example.com:60000:bucket12345").unwrap(),
-        (None, "example.com:60000","bucket12345",BucketStyle::Subdomain))
+        (None, "example.com:60000","bucket12345",Some(BucketStyle::Subdomain),None))
+    }
+    #[test]
+    fn test_key_prefix_parse_remote_url() {
+        assert_eq!(parse_remote_url("s3://eu-west-2/bucket/teams/infra/repo.git").unwrap(),
+        (None, "eu-west-2","bucket",None,Some("teams/infra/repo.git")));
+        assert_eq!(parse_remote_url("s3://profile@https://s3.eu-west-2.amazonaws.com/bucket/repo/").unwrap(),
+        (Some("profile"), "https://s3.eu-west-2.amazonaws.com","bucket",None,Some("repo")));
+        assert_eq!(parse_remote_url("s3://s3.region.amazonaws.com:bucket/teams/repo").unwrap(),
+        (None, "s3.region.amazonaws.com","bucket",Some(BucketStyle::Subdomain),Some("teams/repo")));
+        assert_eq!(parse_remote_url("s3://eu-west-2/bucket/").unwrap(),
+        (None, "eu-west-2","bucket",None,None));
+    }
+    #[test]
+    fn test_url_path_parse_remote_url() {
+        // Other endpoints can have a path, the bucket is the last component
+        assert_eq!(parse_remote_url("s3://example.com/s3/url/bucket12345").unwrap(),
+        (None, "example.com/s3/url","bucket12345",None,None));
+        assert_eq!(parse_remote_url("s3://http://localhost:9000/bucket/").unwrap(),
+        (None, "http://localhost:9000","bucket",None,None));
+    }
+    #[test]
+    fn test_parse_bucket_url() {
+        let is_bucket = |name: &str| name == "shared";
+        assert_eq!(parse_bucket_url("s3://shared", is_bucket), Some((None, "shared", None)));
+        assert_eq!(parse_bucket_url("s3://ci@shared/teams/repo.git", is_bucket),
+        Some((Some("ci"), "shared", Some("teams/repo.git"))));
+        assert_eq!(parse_bucket_url("s3://region/shared", is_bucket), None);
+    }
+    #[test]
+    fn test_is_endpoint_name() {
+        assert!(is_endpoint_name("eu-west-2"));
+        assert!(is_endpoint_name("ceph.example.com"));
+        assert!(is_endpoint_name("localhost:9000"));
+        assert!(is_endpoint_name("http:"));
+        assert!(!is_endpoint_name("shared"));
+        assert!(!is_endpoint_name("ceph-rgw-01"));
+    }
+    #[test]
+    fn test_bucket_region() {
+        assert_eq!(bucket_region(Some("eu-west-2"), None).unwrap(), Region::EuWest2);
+        assert_eq!(bucket_region(None, Some("eu-west-2")).unwrap(), Region::EuWest2);
+        assert_eq!(bucket_region(Some("ceph.example.com"), Some("eu-west-2")).unwrap(),
+        Region::Custom { region: "eu-west-2".to_string(), endpoint: "ceph.example.com".to_string() });
+        assert!(bucket_region(None, None).is_err());
+    }
+    #[test]
+    fn test_bucket_region_signing() {
+        let custom = |region: &str, endpoint: &str| Region::Custom {
+            region: region.to_string(), endpoint: endpoint.to_string(),
+        };
+        // Regions rust-s3 doesn't know
+        assert_eq!(bucket_region(Some("eu-south-1"), None).unwrap(),
+        custom("eu-south-1", "s3.eu-south-1.amazonaws.com"));
+        // AWS hosts sign for their own region
+        assert_eq!(bucket_region(Some("s3.eu-west-2.amazonaws.com"), Some("us-west-1")).unwrap(),
+        custom("eu-west-2", "s3.eu-west-2.amazonaws.com"));
+        assert_eq!(bucket_region(Some("https://s3-ap-east-1.amazonaws.com"), None).unwrap(),
+        custom("ap-east-1", "https://s3-ap-east-1.amazonaws.com"));
+        assert_eq!(bucket_region(Some("s3.amazonaws.com"), None).unwrap(),
+        custom("us-east-1", "s3.amazonaws.com"));
+        // Other services default to us-east-1
+        assert_eq!(bucket_region(Some("http://localhost:9000"), None).unwrap(),
+        custom("us-east-1", "http://localhost:9000"));
+        assert!(bucket_region(None, Some("ceph")).is_err());
+    }
+    #[test]
+    fn test_is_region_name() {
+        for region in &["eu-west-2", "eu-south-1", "us-gov-east-1", "ap-southeast-4", "il-central-1", "cn-north-1"] {
+            assert!(is_region_name(region), "{}", region);
+        }
+        for host in &["ceph-rgw-01", "minio-node-1", "eu-1", "eu-west", "localhost"] {
+            assert!(!is_region_name(host), "{}", host);
+        }
+        // Hosts that look like regions stay custom endpoints
+        assert_eq!(bucket_region(Some("ceph-rgw-01"), Some("eu-west-2")).unwrap(),
+        Region::Custom { region: "eu-west-2".to_string(), endpoint: "ceph-rgw-01".to_string() });
+    }
+    #[test]
+    fn test_parse_url_query() {
+        assert_eq!(parse_url_query("s3://region/bucket").unwrap(), ("s3://region/bucket", None, None));
+        assert_eq!(parse_url_query("s3://ceph:8080/bucket?region=eu-west-2&prefix=/teams/repo.git/").unwrap(),
+        ("s3://ceph:8080/bucket", Some("eu-west-2"), Some("teams/repo.git")));
+        assert!(parse_url_query("s3://ceph/bucket?regoin=eu-west-2").is_err());
+        assert!(parse_url_query("s3://ceph/bucket?region=").is_err());
+        assert!(parse_url_query("s3://ceph/bucket?prefix=/").is_err());
+    }
+    #[test]
+    fn test_invalid_parse_remote_url() {
+        assert!(parse_remote_url("s3://region").is_err());
+        assert!(parse_remote_url("s3://region/").is_err());
+        assert!(parse_remote_url("s3:///bucket").is_err());
+    }
+    #[test]
+    fn test_commit_object_references() {
+        let commit = b"tree 4b825dc642cb6eb9a060e54bf8d69288fbee4904\n\
+parent 1f4830120dec56eff7525151329d5cd27afa5184\n\
+author t <t@t> 1600000000 +0000\n\
+committer t <t@t> 1600000000 +0000\n\
+\n\
+message\n";
+        let tree: ObjectId = "4b825dc642cb6eb9a060e54bf8d69288fbee4904".parse().unwrap();
+        let parent: ObjectId = "1f4830120dec56eff7525151329d5cd27afa5184".parse().unwrap();
+        assert_eq!(object_references(Kind::Commit, commit).unwrap(),
+        vec![(tree, Kind::Tree), (parent, Kind::Commit)])
+    }
+    #[test]
+    fn test_tree_object_references() {
+        let blob: ObjectId = "1f4830120dec56eff7525151329d5cd27afa5184".parse().unwrap();
+        let subtree: ObjectId = "4b825dc642cb6eb9a060e54bf8d69288fbee4904".parse().unwrap();
+        let mut tree = b"100644 a\0".to_vec();
+        tree.extend_from_slice(blob.as_slice());
+        tree.extend_from_slice(b"40000 d\0");
+        tree.extend_from_slice(subtree.as_slice());
+        assert_eq!(object_references(Kind::Tree, &tree).unwrap(),
+        vec![(blob, Kind::Blob), (subtree, Kind::Tree)])
+    }
+    #[test]
+    fn test_gitlink_object_references() {
+        let blob: ObjectId = "1f4830120dec56eff7525151329d5cd27afa5184".parse().unwrap();
+        let submodule: ObjectId = "4b825dc642cb6eb9a060e54bf8d69288fbee4904".parse().unwrap();
+        let mut tree = b"100644 .gitmodules\0".to_vec();
+        tree.extend_from_slice(blob.as_slice());
+        tree.extend_from_slice(b"160000 vendor\0");
+        tree.extend_from_slice(submodule.as_slice());
+        assert_eq!(object_references(Kind::Tree, &tree).unwrap(), vec![(blob, Kind::Blob)])
+    }
+    #[test]
+    fn test_tag_object_references() {
+        let tag = b"object 4b825dc642cb6eb9a060e54bf8d69288fbee4904\n\
+type tree\n\
+tag v1.0\n\
+tagger t <t@t> 1600000000 +0000\n\
+\n\
+message\n";
+        let tree: ObjectId = "4b825dc642cb6eb9a060e54bf8d69288fbee4904".parse().unwrap();
+        assert_eq!(object_references(Kind::Tag, tag).unwrap(), vec![(tree, Kind::Tree)])
+    }
+    #[test]
+    fn test_decode_loose_object() {
+        // `git hash-object` of "hello\n"
+        let blob: ObjectId = "ce013625030ba8dba906f756967f9e9ca394464a".parse().unwrap();
+        let ids = stored_object_ids(&[Kind::Blob, Kind::Tree], &mut io::Cursor::new(b"hello\n"), 0, 6).unwrap();
+        assert_eq!(ids[0], blob);
+        assert_ne!(ids[1], blob);
+        let decode = |id, stored: &[u8]| decode_loose_object(id, &mut io::Cursor::new(stored));
+        assert_eq!(decode(&blob, b"hello\n").unwrap(), (Kind::Blob, 0, 6));
+        assert_eq!(decode(&blob, b"blob 6\0hello\n").unwrap(), (Kind::Blob, 7, 6));
+        assert!(decode(&blob, b"goodbye\n").is_err());
+        // The empty tree
+        let tree: ObjectId = "4b825dc642cb6eb9a060e54bf8d69288fbee4904".parse().unwrap();
+        assert_eq!(decode(&tree, b"").unwrap(), (Kind::Tree, 0, 0));
+    }
+    #[test]
+    fn test_blob_object_references() {
+        assert!(object_references(Kind::Blob, b"data").unwrap().is_empty())
+    }
+    #[test]
+    fn test_parallel_map_keeps_order() {
+        let items: Vec<usize> = (0..100).collect();
+        let doubled = block_on(parallel_map(items, 8, |i| async move { Ok(i * 2) })).unwrap();
+        assert_eq!(doubled, (0..100).map(|i| i * 2).collect::<Vec<_>>());
+    }
+    #[test]
+    fn test_parallel_map_error() {
+        let items: Vec<usize> = (0..100).collect();
+        let result = block_on(parallel_map(items, 8, |i| async move {
+            if i == 10 { Err(Error::msg("failed")) } else { Ok(i) }
+        }));
+        assert!(result.is_err());
     }
 }
@@ -14,7 +14,7 @@ fn main() -> Result<()> {
     let verbose: usize = match env::var("GIT_S3_LOG_LEVEL") {
         Ok(s) if opts.verbose == 0 => s.parse()
             .with_context(|| format!("Unable to parse `GIT_S3_LOG_LEVEL={}` to usize", s))?,
-        Ok(_) => opts.verbose,
+        Ok(_) | Err(env::VarError::NotPresent) => opts.verbose,
         Err(e) => return Err(e).context("Error parsing log level"),
     };
 
@@ -25,8 +25,12 @@ fn main() -> Result<()> {
         .init()
         .unwrap();
 
+    let set_head = opts.set_head.clone();
+    let init_manifest = opts.init_manifest;
+    let migrate_objects = opts.migrate_objects;
+
     // Build git_s3 object
-    let remote =
+    let mut remote =
         match git_s3::remote::Remote::new(opts) {
             Ok(content) => content,
             Err(err) => return Err(Error::msg(
@@ -34,6 +38,26 @@ fn main() -> Result<()> {
                 )),
         };
 
+    // Every transfer with the bucket runs on this
+    let mut runtime = tokio::runtime::Builder::new()
+        .basic_scheduler()
+        .enable_all()
+        .build()
+        .context("Unable to start async runtime")?;
+
+    // Point the remote HEAD elsewhere instead of serving git
+    if let Some(branch) = set_head {
+        return runtime.block_on(remote.set_remote_head(&branch))
+    }
+    // Switch the remote to a ref manifest instead of serving git
+    if init_manifest {
+        return runtime.block_on(remote.init_manifest())
+    }
+    // Pack up old loose objects instead of serving git
+    if migrate_objects {
+        return runtime.block_on(remote.migrate_loose_objects())
+    }
+
     // Loop over commands on stdin, do work, return when done
-    remote.run()
+    runtime.block_on(remote.run())
 }